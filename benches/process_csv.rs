@@ -0,0 +1,93 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use money_project::{
+    account::AccountDatabase,
+    input::{process_csv, process_csv_records, process_file, InputOptions},
+    processor::{ApplyOptions, Processor, ProcessorConfig},
+};
+
+const ROWS: u32 = 100_000;
+
+/// Deposits and withdrawals spread over a thousand clients.
+fn synthetic_csv(rows: u32) -> String {
+    let mut csv = String::from("type, client, tx, amount\n");
+    for tx in 0..rows {
+        let client = tx % 1000;
+        let transaction_type = if tx % 4 == 3 { "withdrawal" } else { "deposit" };
+        csv.push_str(&format!("{transaction_type}, {client}, {tx}, 1.2345\n"));
+    }
+    csv
+}
+
+fn run(process: impl Fn(&Processor, &[u8]) -> anyhow::Result<()>, input: &str) {
+    run_with(process, input, ProcessorConfig::default());
+}
+
+fn run_with(
+    process: impl Fn(&Processor, &[u8]) -> anyhow::Result<()>,
+    input: &str,
+    config: ProcessorConfig,
+) {
+    let database = AccountDatabase::default();
+    let processor = Processor::with_config(database, config);
+    process(&processor, input.as_bytes()).unwrap();
+    processor.close();
+}
+
+fn bench_process_csv(c: &mut Criterion) {
+    let input = synthetic_csv(ROWS);
+    let mut group = c.benchmark_group("process_csv");
+    group.throughput(Throughput::Elements(ROWS.into()));
+    group.sample_size(10);
+    group.bench_function("deserialize", |b| {
+        b.iter(|| run(|processor, input| process_csv(processor, input), &input))
+    });
+    group.bench_function("records", |b| {
+        b.iter(|| {
+            run(
+                |processor, input| process_csv_records(processor, input),
+                &input,
+            )
+        })
+    });
+    group.bench_function("balances_only", |b| {
+        b.iter(|| {
+            let config = ProcessorConfig::builder()
+                .apply_options(ApplyOptions::builder().balances_only(true).build())
+                .build();
+            run_with(
+                |processor, input| process_csv(processor, input),
+                &input,
+                config,
+            )
+        })
+    });
+    group.finish();
+}
+
+/// Reading a file through a buffer vs memory-mapping it.
+fn bench_process_file(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("input.csv");
+    std::fs::write(&path, synthetic_csv(ROWS)).unwrap();
+
+    let mut group = c.benchmark_group("process_file");
+    group.throughput(Throughput::Elements(ROWS.into()));
+    group.sample_size(10);
+    let mut bench = |name: &str, options: InputOptions| {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let processor = Processor::new(AccountDatabase::default());
+                process_file(&processor, &path, &options).unwrap();
+                processor.close();
+            })
+        });
+    };
+    bench("file", InputOptions::default());
+    if cfg!(feature = "mmap") {
+        bench("mmap", InputOptions::builder().mmap(true).build());
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_process_csv, bench_process_file);
+criterion_main!(benches);
@@ -1,23 +1,41 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Display,
-    io::Write,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
     str::FromStr,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use serde_with::{DeserializeFromStr, SerializeDisplay};
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
+use serde_with::SerializeDisplay;
 
 use crate::{
-    currency::Currency,
-    transaction::{Transaction, TransactionId},
+    clock::{Clock, SystemClock},
+    currency::{Currency, DECIMAL_PLACES},
+    error::TransactionError,
+    processor::{apply_transaction, apply_transaction_with, check_stateless, ApplyOptions},
+    store::{AccountStore, MemoryStore},
+    transaction::{Transaction, TransactionId, TransactionType},
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SerializeDisplay, DeserializeFromStr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, SerializeDisplay)]
 pub struct ClientId(u16);
 
+impl ClientId {
+    /// The shard (out of `shards`) that this client belongs to.
+    pub fn shard(&self, shards: usize) -> usize {
+        self.0 as usize % shards
+    }
+}
+
 impl From<u16> for ClientId {
     fn from(value: u16) -> Self {
         Self(value)
@@ -38,6 +56,39 @@ impl FromStr for ClientId {
     }
 }
 
+/// Accepts either a string (CSV, JSON) or an integer (TOML).
+impl<'de> Deserialize<'de> for ClientId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ClientIdVisitor;
+
+        impl Visitor<'_> for ClientIdVisitor {
+            type Value = ClientId;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a client id")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(ClientId)
+                    .map_err(|_| E::custom("invalid client id"))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(ClientId)
+                    .map_err(|_| E::custom("invalid client id"))
+            }
+        }
+
+        deserializer.deserialize_any(ClientIdVisitor)
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum AccountStatus {
@@ -57,8 +108,19 @@ pub struct Account {
     /// Transaction cache for lookups.
     #[builder(skip)]
     pub transactions: HashMap<TransactionId, Transaction>,
+    /// Open disputes, with the amount each one holds.
+    #[builder(skip)]
+    pub disputes: HashMap<TransactionId, Currency>,
+    /// Reversed transactions. These stay in the cache (so that their ids
+    /// can't be reused), but their funds are gone.
+    #[builder(skip)]
+    #[serde(default)]
+    pub reversed: BTreeSet<TransactionId>,
+    /// The most recent transaction, if it was a deposit or withdrawal. Only
+    /// this transaction can be reversed.
     #[builder(skip)]
-    pub disputes: HashSet<TransactionId>,
+    #[serde(default)]
+    pub last_movement: Option<TransactionId>,
     #[builder(skip)]
     pub available: Currency,
     #[builder(skip)]
@@ -67,9 +129,233 @@ pub struct Account {
     pub total: Currency,
     #[builder(skip)]
     status: AccountStatus,
+    /// State as of the end of any history that has been compacted.
+    #[builder(skip)]
+    #[serde(default)]
+    pub compacted: Option<CompactedSummary>,
+    /// Rolling hash of every transaction recorded in the history. See
+    /// `audit_hash`.
+    #[builder(skip)]
+    #[serde(default)]
+    audit_hash: u64,
+}
+
+/// The result of the oldest part of an account's history, which has been
+/// discarded to bound memory use. See `Account::compact`.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactedSummary {
+    /// Number of history entries that have been compacted.
+    pub count: usize,
+    pub available: Currency,
+    pub held: Currency,
+    pub total: Currency,
+    pub locked: bool,
+    /// Disputes that were open at the end of the compacted history.
+    pub disputes: HashMap<TransactionId, Currency>,
+    /// Compacted deposits and withdrawals that are still needed, because
+    /// they are in dispute or referenced by the remaining history.
+    pub transactions: HashMap<TransactionId, Transaction>,
+    /// Reversed transactions, and the most recent deposit or withdrawal, as
+    /// of the end of the compacted history. See `Account::reversed`.
+    #[serde(default)]
+    pub reversed: BTreeSet<TransactionId>,
+    #[serde(default)]
+    pub last_movement: Option<TransactionId>,
+    /// The audit hash as of the end of the compacted history.
+    #[serde(default)]
+    pub audit_hash: u64,
 }
 
 impl Account {
+    /// An account in the state described by the summary, with no history.
+    fn from_compacted(client_id: ClientId, summary: &CompactedSummary) -> Self {
+        Self {
+            client_id,
+            history: Vec::new(),
+            transactions: summary.transactions.clone(),
+            disputes: summary.disputes.clone(),
+            reversed: summary.reversed.clone(),
+            last_movement: summary.last_movement,
+            available: summary.available,
+            held: summary.held,
+            total: summary.total,
+            status: match summary.locked {
+                true => AccountStatus::Locked,
+                false => AccountStatus::Active,
+            },
+            compacted: Some(summary.clone()),
+            audit_hash: summary.audit_hash,
+        }
+    }
+
+    /// A new account with an opening balance (all available), e.g. carried
+    /// over from another system.
+    ///
+    /// The balance is recorded as the compacted state, so that replaying the
+    /// history (and `AccountDatabase::conservation_check`) still accounts for it.
+    pub fn with_opening_balance(client_id: ClientId, available: Currency) -> Self {
+        let summary = CompactedSummary {
+            available,
+            total: available,
+            ..Default::default()
+        };
+        Self::from_compacted(client_id, &summary)
+    }
+
+    /// A new account to replay the history into, starting from the
+    /// compacted summary if there is one.
+    fn replay_start(&self) -> Self {
+        match &self.compacted {
+            Some(summary) => Self::from_compacted(self.client_id, summary),
+            None => Account::builder().client_id(self.client_id).build(),
+        }
+    }
+
+    /// Compact all but the most recent `keep` history entries (at least one)
+    /// into the `compacted` summary.
+    ///
+    /// Compacted deposits and withdrawals are dropped from the transaction
+    /// cache unless they are still in dispute or referenced by the remaining
+    /// history. This means they can no longer be disputed, and their ids are
+    /// no longer checked for duplicates.
+    pub fn compact(&mut self, keep: usize) {
+        let keep = keep.max(1);
+        if self.history.len() <= keep {
+            return;
+        }
+
+        let recent = self.history.split_off(self.history.len() - keep);
+        let mut state = self.replay_start();
+        let count = state.compacted.as_ref().map_or(0, |x| x.count) + self.history.len();
+        for transaction in self.history.drain(..) {
+            apply_transaction(transaction, &mut state).ok();
+        }
+        self.history = recent;
+
+        let referenced: HashSet<TransactionId> =
+            self.history.iter().map(|x| x.transaction_id).collect();
+        let mut transactions = state.transactions;
+        transactions.retain(|id, _| state.disputes.contains_key(id) || referenced.contains(id));
+        self.transactions.retain(|id, _| {
+            transactions.contains_key(id)
+                || self.disputes.contains_key(id)
+                || referenced.contains(id)
+        });
+
+        self.compacted = Some(CompactedSummary {
+            count,
+            available: state.available,
+            held: state.held,
+            total: state.total,
+            locked: state.status == AccountStatus::Locked,
+            disputes: state.disputes,
+            transactions,
+            reversed: state.reversed,
+            last_movement: state.last_movement,
+            audit_hash: state.audit_hash,
+        });
+    }
+
+    /// Rebuild the transaction cache, disputes, balances and locked state by
+    /// replaying the history (from the compacted summary, if any).
+    ///
+    /// Use this to restore an account that was only partially persisted.
+    pub fn rebuild_cache(&mut self) {
+        let mut state = self.replay_start();
+        for transaction in &self.history {
+            if let Err(e) = apply_transaction(transaction.clone(), &mut state) {
+                tracing::warn!(
+                    "failed to replay transaction {} for client {}: {e:#}",
+                    transaction.transaction_id,
+                    self.client_id
+                );
+            }
+        }
+        self.status = state.status;
+        self.transactions = state.transactions;
+        self.disputes = state.disputes;
+        self.reversed = state.reversed;
+        self.last_movement = state.last_movement;
+        self.available = state.available;
+        self.held = state.held;
+        self.total = state.total;
+        self.audit_hash = state.audit_hash;
+    }
+
+    /// Append an applied transaction to the history, chaining it onto the
+    /// audit hash.
+    pub(crate) fn record(&mut self, transaction: Transaction) {
+        self.audit_hash = chain_audit_hash(self.audit_hash, &transaction);
+        self.history.push(transaction);
+    }
+
+    /// A hash chaining together every transaction in the history (including
+    /// any that have been compacted), as 16 hex digits.
+    ///
+    /// Identical histories always give the same hash, so an edited history
+    /// can be detected by comparing this with `recompute_audit_hash`. The
+    /// hash is not cryptographic, so it won't stop a determined forger.
+    pub fn audit_hash(&self) -> String {
+        format!("{:016x}", self.audit_hash)
+    }
+
+    /// Recompute the audit hash from the history as it is now.
+    pub fn recompute_audit_hash(&self) -> String {
+        let start = self.compacted.as_ref().map_or(0, |x| x.audit_hash);
+        let hash = self.history.iter().fold(start, chain_audit_hash);
+        format!("{hash:016x}")
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Approximate heap and inline bytes used by this account. See
+    /// `AccountDatabase::estimated_memory`.
+    pub fn estimated_memory(&self) -> usize {
+        let transaction = |t: &Transaction| {
+            size_of::<Transaction>() + t.reference.as_ref().map_or(0, String::capacity)
+        };
+        // Hash tables store the key and value, plus a control byte.
+        let cache = |transactions: &HashMap<TransactionId, Transaction>| {
+            transactions.capacity() * (size_of::<TransactionId>() + 1)
+                + transactions.values().map(transaction).sum::<usize>()
+        };
+        let disputes = |disputes: &HashMap<TransactionId, Currency>| {
+            disputes.capacity() * (size_of::<TransactionId>() + size_of::<Currency>() + 1)
+        };
+
+        let history = (self.history.capacity() - self.history.len()) * size_of::<Transaction>()
+            + self.history.iter().map(transaction).sum::<usize>();
+        let compacted = self.compacted.as_ref().map_or(0, |summary| {
+            size_of::<CompactedSummary>()
+                + cache(&summary.transactions)
+                + disputes(&summary.disputes)
+        });
+        size_of::<Self>()
+            + history
+            + cache(&self.transactions)
+            + disputes(&self.disputes)
+            + compacted
+    }
+
+    /// Fraction of the total that is held (0 when the total is zero).
+    pub fn held_ratio(&self) -> Currency {
+        self.held
+            .as_decimal()
+            .checked_div(self.total.as_decimal())
+            .map(|ratio| Currency::from_decimal(ratio.normalize()))
+            .unwrap_or_default()
+    }
+
+    /// Whether any money has ever moved through this account. An account
+    /// without activity was only created by a transaction that then failed
+    /// (e.g. a zero-amount deposit).
+    pub fn has_activity(&self) -> bool {
+        !self.history.is_empty() || !self.transactions.is_empty() || self.compacted.is_some()
+    }
+
     pub fn is_locked(&self) -> bool {
         self.status == AccountStatus::Locked
     }
@@ -77,49 +363,2133 @@ impl Account {
     pub fn freeze(&mut self) {
         self.status = AccountStatus::Locked
     }
+
+    pub fn summary(&self) -> AccountSummary {
+        AccountSummary {
+            client_id: self.client_id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.is_locked(),
+        }
+    }
+
+    /// All transactions of the given type from the history, in order.
+    ///
+    /// This is a single O(n) pass over the history rather than a maintained
+    /// index, which keeps `apply_transaction` cheap.
+    pub fn transactions_of_type(
+        &self,
+        transaction_type: TransactionType,
+    ) -> impl Iterator<Item = &Transaction> {
+        self.history
+            .iter()
+            .filter(move |x| x.transaction_type == transaction_type)
+    }
+
+    /// The `(available, held, total)` balances that applying the transaction
+    /// would result in, without changing this account.
+    pub fn preview(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<(Currency, Currency, Currency), TransactionError> {
+        let mut account = self.clone();
+        apply_transaction(transaction.clone(), &mut account)?;
+        Ok((account.available, account.held, account.total))
+    }
+
+    /// Totals by transaction type, computed in a single pass over the history.
+    ///
+    /// Compacted history is not included.
+    pub fn activity(&self) -> AccountActivity {
+        let mut activity = AccountActivity::default();
+        for transaction in &self.history {
+            match transaction.transaction_type {
+                TransactionType::Deposit => activity.deposited += transaction.amount,
+                TransactionType::Withdrawal => activity.withdrawn += transaction.amount,
+                TransactionType::Dispute => activity.disputes += 1,
+                TransactionType::Reversal => {
+                    if let Some(x) = self.transactions.get(&transaction.transaction_id) {
+                        match x.transaction_type {
+                            TransactionType::Deposit => activity.deposited -= transaction.amount,
+                            _ => activity.withdrawn -= transaction.amount,
+                        }
+                    }
+                }
+                TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::Adjustment => {}
+            }
+        }
+        activity
+    }
+
+    /// Ids of all deposits in the transaction cache, sorted.
+    pub fn deposit_ids(&self) -> Vec<TransactionId> {
+        let mut ids: Vec<TransactionId> = self
+            .transactions
+            .values()
+            .filter(|x| x.transaction_type == TransactionType::Deposit)
+            .map(|x| x.transaction_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// When the given open dispute was raised, if the dispute had a timestamp.
+    pub fn dispute_time(&self, transaction_id: TransactionId) -> Option<DateTime<Utc>> {
+        if !self.disputes.contains_key(&transaction_id) {
+            return None;
+        }
+
+        self.history
+            .iter()
+            .rev()
+            .find(|x| {
+                x.transaction_id == transaction_id && x.transaction_type == TransactionType::Dispute
+            })
+            .and_then(|x| x.timestamp)
+    }
+
+    /// Serialize the full account state, including history and the
+    /// transaction cache, as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("account serialization cannot fail")
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(json).context("failed to parse account JSON")
+    }
+
+    /// Verify that the account is internally consistent, without panicking.
+    ///
+    /// This checks the balance arithmetic, that the held balance is exactly
+    /// what the open disputes hold, the locked state, and that replaying the
+    /// history from scratch produces an identical account.
+    pub fn check_invariants(&self) -> anyhow::Result<()> {
+        if self.available != self.total - self.held {
+            return Err(anyhow::anyhow!(
+                "available ({}) does not equal total ({}) minus held ({})",
+                self.available,
+                self.total,
+                self.held
+            ));
+        }
+
+        let mut disputed = Currency::ZERO;
+        for (transaction_id, hold) in &self.disputes {
+            if !self.transactions.contains_key(transaction_id) {
+                return Err(anyhow::anyhow!(
+                    "disputed tx {transaction_id} is not in the cache"
+                ));
+            }
+            disputed = disputed
+                .checked_add(*hold)
+                .context("overflow summing disputed amounts")?;
+        }
+        if disputed != self.held {
+            return Err(anyhow::anyhow!(
+                "held ({}) does not equal the sum of open disputes ({disputed})",
+                self.held
+            ));
+        }
+
+        // Account should only be locked if a chargeback froze it, and if so,
+        // the chargeback should be the last transaction.
+        let last_froze = self
+            .history
+            .last()
+            .is_some_and(|x| x.transaction_type == TransactionType::Chargeback && x.froze_account);
+        if last_froze != self.is_locked() {
+            return Err(anyhow::anyhow!(
+                "locked state does not match history (locked: {})",
+                self.is_locked()
+            ));
+        }
+
+        let mut new_account = self.replay_start();
+        for transaction in &self.history {
+            apply_transaction(transaction.clone(), &mut new_account).ok();
+        }
+        if self != &new_account {
+            return Err(anyhow::anyhow!(
+                "replaying history does not reproduce the account"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Chain a transaction onto an audit hash, using 64-bit FNV-1a over the
+/// previous hash and the transaction's JSON.
+fn chain_audit_hash(previous: u64, transaction: &Transaction) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let json = serde_json::to_vec(transaction).expect("transaction serialization cannot fail");
+    previous
+        .to_be_bytes()
+        .iter()
+        .chain(&json)
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+        })
+}
+
+/// Replace the serialized transaction cache in `value` with a sorted array.
+/// JSON object keys (such as the disputes) are already sorted by `serde_json`.
+fn stabilize(value: &mut serde_json::Value, transactions: &HashMap<TransactionId, Transaction>) {
+    let mut transactions: Vec<&Transaction> = transactions.values().collect();
+    transactions.sort_by_key(|x| x.transaction_id);
+    value["transactions"] = serde_json::json!(transactions);
+}
+
+/// A difference between two databases. See `AccountDatabase::diff`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountDiff {
+    /// The client only exists in the other database.
+    Added(AccountSummary),
+    /// The client only exists in this database.
+    Removed(AccountSummary),
+    /// The client's balances or locked state differ.
+    Changed {
+        before: AccountSummary,
+        after: AccountSummary,
+    },
+}
+
+/// Per-type totals from an account's history. See `Account::activity`.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccountActivity {
+    pub deposited: Currency,
+    pub withdrawn: Currency,
+    pub disputes: usize,
+}
+
+/// A column in the output of `AccountDatabase::output_data_with`.
+/// The header for each column is its lowercase name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    /// Total deposited, from the history.
+    Deposited,
+    /// Total withdrawn, from the history.
+    Withdrawn,
+    /// Number of disputes raised, from the history.
+    Disputes,
+    /// Display name from `OutputOptions::aliases`, or the client id if it
+    /// has none.
+    Name,
+    /// Fraction of the total that is held, e.g. 0.4 for 60 available and
+    /// 40 held. Shown as 0 when the total is zero.
+    #[strum(serialize = "held_ratio")]
+    HeldRatio,
+}
+
+impl OutputColumn {
+    /// The standard output columns.
+    pub const DEFAULT: &[Self] = &[
+        Self::Client,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+    ];
+
+    /// The standard columns plus a per-type breakdown of the history.
+    pub const WIDE: &[Self] = &[
+        Self::Client,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+        Self::Deposited,
+        Self::Withdrawn,
+        Self::Disputes,
+    ];
+
+    fn needs_activity(&self) -> bool {
+        matches!(self, Self::Deposited | Self::Withdrawn | Self::Disputes)
+    }
+}
+
+/// How the `locked` output column is written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum LockedFormat {
+    /// "true" or "false".
+    #[default]
+    TrueFalse,
+    /// "1" or "0".
+    OneZero,
+    /// "locked" or "active".
+    ActiveLocked,
+}
+
+impl LockedFormat {
+    fn format(&self, locked: bool) -> &'static str {
+        match (self, locked) {
+            (Self::TrueFalse, true) => "true",
+            (Self::TrueFalse, false) => "false",
+            (Self::OneZero, true) => "1",
+            (Self::OneZero, false) => "0",
+            (Self::ActiveLocked, true) => "locked",
+            (Self::ActiveLocked, false) => "active",
+        }
+    }
+}
+
+/// Options controlling how `AccountDatabase::output_data_with` formats
+/// the output.
+#[non_exhaustive]
+#[derive(Debug, Clone, bon::Builder)]
+pub struct OutputOptions {
+    /// Always show the full number of decimal places (e.g. "1.5000").
+    #[builder(default)]
+    pub fixed_decimals: bool,
+    /// Which columns to write, in order.
+    #[builder(default = OutputColumn::DEFAULT.to_vec())]
+    pub columns: Vec<OutputColumn>,
+    /// Leave out accounts without any activity (see `Account::has_activity`).
+    /// Otherwise they are written, with a warning.
+    #[builder(default)]
+    pub omit_inactive: bool,
+    /// Display names for the `name` column. See `input::load_aliases`.
+    #[builder(default)]
+    pub aliases: HashMap<ClientId, String>,
+    /// How the `locked` column is written.
+    #[builder(default)]
+    pub locked_format: LockedFormat,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Point-in-time balances for a single account.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSummary {
+    pub client_id: ClientId,
+    pub available: Currency,
+    pub held: Currency,
+    pub total: Currency,
+    pub locked: bool,
+}
+
+impl Display for AccountSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Client {}", self.client_id)?;
+        writeln!(f, "  Available: {}", self.available)?;
+        writeln!(f, "  Held:      {}", self.held)?;
+        writeln!(f, "  Total:     {}", self.total)?;
+        writeln!(f, "  Locked:    {}", self.locked)
+    }
+}
+
+/// Aggregate balances across every account in the database.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub available: Currency,
+    pub held: Currency,
+    pub total: Currency,
+}
+
+impl Display for Totals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Accounts:  {} ({} locked)",
+            self.accounts, self.locked_accounts
+        )?;
+        writeln!(f, "Available: {}", self.available)?;
+        writeln!(f, "Held:      {}", self.held)?;
+        writeln!(f, "Total:     {}", self.total)
+    }
 }
 
 /// Simulated database of accounts.
-#[derive(Default, Clone)]
-pub struct AccountDatabase {
+///
+/// Accounts are kept in an `AccountStore`, which is in memory by default.
+pub struct AccountDatabase<S: AccountStore = MemoryStore> {
     /// Account data, keyed by client id.
-    /// Wrapped in RwLock because account operations are far more common than
-    /// account creation/deletion.
-    /// Each account is wrapped in Arc<Mutex<>> to allow operations on different
-    /// accounts concurrently.
-    data: Arc<RwLock<HashMap<ClientId, Arc<Mutex<Account>>>>>,
+    store: Arc<S>,
+    /// Number of decimal places used for amounts in output.
+    /// This only affects presentation; amounts are stored at full precision.
+    precision: u32,
+    /// Accounts whose mutex was poisoned and subsequently recovered.
+    /// These may be inconsistent and should be checked.
+    recovered: Arc<Mutex<HashSet<ClientId>>>,
+    /// Account that collects withdrawal fees, if any.
+    fee_account: Option<ClientId>,
+    /// Next candidate transaction id for a fee deposit. These count down
+    /// from `u32::MAX`, to stay clear of the ids used by the feed.
+    fee_ids: Arc<AtomicU32>,
+    /// Source of the current time for time-dependent operations.
+    clock: Arc<dyn Clock>,
+}
+
+// Not derived, since that would require `S: Clone`.
+impl<S: AccountStore> Clone for AccountDatabase<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            precision: self.precision,
+            recovered: self.recovered.clone(),
+            fee_account: self.fee_account,
+            fee_ids: self.fee_ids.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl Default for AccountDatabase {
+    fn default() -> Self {
+        Self::with_precision(DECIMAL_PLACES)
+    }
 }
 
 impl AccountDatabase {
+    pub fn with_precision(precision: u32) -> Self {
+        Self::with_store(MemoryStore::default(), precision)
+    }
+}
+
+impl<S: AccountStore> AccountDatabase<S> {
+    /// Keep accounts in `store` instead of in memory.
+    pub fn with_store(store: S, precision: u32) -> Self {
+        Self {
+            store: Arc::new(store),
+            precision,
+            recovered: Default::default(),
+            fee_account: None,
+            fee_ids: Arc::new(AtomicU32::new(u32::MAX)),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use the given clock instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The current time, according to this database's clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Credit withdrawal fees (see `ApplyOptions::withdrawal_fee`) to the
+    /// given account, so that the fees are not lost from the totals.
+    pub fn with_fee_account(mut self, client_id: ClientId) -> Self {
+        self.fee_account = Some(client_id);
+        self
+    }
+
+    pub fn precision(&self) -> u32 {
+        self.precision
+    }
+
+    pub fn fee_account(&self) -> Option<ClientId> {
+        self.fee_account
+    }
+
+    /// The underlying account store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the account for `client_id`, creating it if it doesn't exist.
     pub fn account(&self, client_id: ClientId) -> Arc<Mutex<Account>> {
-        if let Some(account) = self.data.read().expect("lock poisoned").get(&client_id) {
-            return account.clone();
+        self.store.account(client_id)
+    }
+
+    /// Look up an existing account without creating it.
+    pub fn get_account(&self, client_id: ClientId) -> Option<Arc<Mutex<Account>>> {
+        self.store.get_account(client_id)
+    }
+
+    /// Apply a single transaction to its account and return the result.
+    ///
+    /// Unlike `Processor::send_transaction`, this runs on the calling thread.
+    pub fn apply(&self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.apply_with(transaction, &ApplyOptions::default())
+    }
+
+    /// Same as `apply`, with the specified options.
+    ///
+    /// Disputes, resolves, chargebacks and reversals never create an account, since
+    /// they can only refer to an existing transaction.
+    pub fn apply_with(
+        &self,
+        transaction: Transaction,
+        options: &ApplyOptions,
+    ) -> Result<(), TransactionError> {
+        self.apply_and_snapshot(transaction, options).map(|_| ())
+    }
+
+    /// Same as `apply_with`, returning the client's balances as they were
+    /// immediately after the transaction was applied (taken under the same
+    /// lock, so they are never torn by a concurrent update).
+    pub(crate) fn apply_and_snapshot(
+        &self,
+        mut transaction: Transaction,
+        options: &ApplyOptions,
+    ) -> Result<AccountSummary, TransactionError> {
+        check_stateless(&transaction, options)?;
+        if options.daily_withdrawal_limit.is_some()
+            && transaction.transaction_type == TransactionType::Withdrawal
+        {
+            transaction.timestamp.get_or_insert_with(|| self.now());
         }
+        let account_mutex = match transaction.transaction_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Adjustment => self.account(transaction.client_id),
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Reversal => self.get_account(transaction.client_id).ok_or(
+                TransactionError::TransactionNotFound {
+                    client_id: transaction.client_id,
+                    transaction_id: transaction.transaction_id,
+                },
+            )?,
+        };
+        let client_id = transaction.client_id;
+        let fee = match (transaction.transaction_type, options.withdrawal_fee) {
+            (TransactionType::Withdrawal, Some(fee)) if fee.get() > Currency::ZERO => {
+                self.fee_account.map(|fee_account| (fee_account, fee.get()))
+            }
+            _ => None,
+        };
+
+        match fee {
+            Some((fee_account, fee)) if fee_account != client_id => {
+                // Both accounts are locked in client id order, so that
+                // concurrent withdrawals can't deadlock on the fee account.
+                let fee_mutex = self.account(fee_account);
+                let (mut account, mut fee_account) = match client_id < fee_account {
+                    true => {
+                        let account = self.lock_account(&account_mutex);
+                        (account, self.lock_account(&fee_mutex))
+                    }
+                    false => {
+                        let fee_account = self.lock_account(&fee_mutex);
+                        (self.lock_account(&account_mutex), fee_account)
+                    }
+                };
+                // Checked first, since the withdrawal can't be undone if the
+                // fee can't be credited.
+                if fee_account.is_locked() {
+                    return Err(TransactionError::FeeAccountLocked);
+                }
+                apply_transaction_with(transaction, &mut account, options)?;
+                self.credit_fee(&mut fee_account, client_id, fee)?;
+                Ok(account.summary())
+            }
+            _ => {
+                let mut account = self.lock_account(&account_mutex);
+                apply_transaction_with(transaction, &mut account, options)?;
+                // A fee paid by the fee account itself is credited straight back.
+                if let Some((_, fee)) = fee {
+                    self.credit_fee(&mut account, client_id, fee)?;
+                }
+                Ok(account.summary())
+            }
+        }
+    }
 
-        // Account does not exist, add it.
-        let mut data = self.data.write().expect("lock poisoned");
-        // NOTE: There is a potential race, so don't just insert blindly.
-        data.entry(client_id)
-            .or_insert_with(|| {
-                Arc::new(Mutex::new(Account::builder().client_id(client_id).build()))
+    /// Deposit a withdrawal fee into the (already locked) fee account, under
+    /// a transaction id of its own.
+    fn credit_fee(
+        &self,
+        fee_account: &mut Account,
+        client_id: ClientId,
+        fee: Currency,
+    ) -> Result<(), TransactionError> {
+        let transaction_id = loop {
+            let id = TransactionId::from(self.fee_ids.fetch_sub(1, Ordering::Relaxed));
+            if !fee_account.transactions.contains_key(&id) {
+                break id;
+            }
+        };
+        let deposit = Transaction::builder()
+            .transaction_type(TransactionType::Deposit)
+            .client_id(fee_account.client_id)
+            .transaction_id(transaction_id)
+            .amount(fee)
+            .reference(format!("withdrawal fee from client {client_id}"))
+            .build()?;
+        apply_transaction(deposit, fee_account)
+    }
+
+    /// Lock an account, recovering if the mutex was poisoned by a panic.
+    ///
+    /// A recovered account may be inconsistent, so it is recorded in
+    /// `recovered_accounts()` for later verification by `check_invariants`.
+    pub fn lock_account<'a>(&self, account_mutex: &'a Mutex<Account>) -> MutexGuard<'a, Account> {
+        account_mutex.lock().unwrap_or_else(|poisoned| {
+            account_mutex.clear_poison();
+            let account = poisoned.into_inner();
+            tracing::warn!("recovered poisoned lock for account {}", account.client_id);
+            self.recovered
+                .lock()
+                .expect("lock poisoned")
+                .insert(account.client_id);
+            account
+        })
+    }
+
+    /// A copy of every account, sorted by client id.
+    pub fn snapshot(&self) -> Vec<Account> {
+        let mut accounts: Vec<Account> = self
+            .store
+            .iter()
+            .map(|x| self.lock_account(&x).clone())
+            .collect();
+        accounts.sort_by_key(|x| x.client_id);
+        accounts
+    }
+
+    /// Every account as pretty-printed JSON with a byte-stable layout, for
+    /// golden tests. Accounts are sorted by client id, and dispute sets and
+    /// transaction caches are written as arrays sorted by transaction id.
+    ///
+    /// This is for comparison only; it can't be read back as accounts.
+    pub fn to_stable_json(&self) -> String {
+        let accounts: Vec<serde_json::Value> = self
+            .snapshot()
+            .iter()
+            .map(|account| {
+                let mut value =
+                    serde_json::to_value(account).expect("account serialization cannot fail");
+                stabilize(&mut value, &account.transactions);
+                if let Some(summary) = &account.compacted {
+                    stabilize(&mut value["compacted"], &summary.transactions);
+                }
+                value
             })
-            .clone()
+            .collect();
+        serde_json::to_string_pretty(&accounts).expect("account serialization cannot fail")
     }
 
-    pub fn output_data<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
-        writeln!(writer, "client,available,held,total,locked")?;
-        for account_mutex in self.data.read().expect("lock poisoned").values() {
-            let account = account_mutex.lock().expect("lock poisoned");
-            let client = account.client_id;
-            let available = account.available;
-            let held = account.held;
-            let total = account.total;
-            let locked = account.is_locked();
+    /// Compare the balances and locked state of every account with those in
+    /// `other`, ignoring history. Differences are sorted by client id.
+    pub fn diff<T: AccountStore>(&self, other: &AccountDatabase<T>) -> Vec<AccountDiff> {
+        let mut before = self.all_summaries();
+        let mut diffs = Vec::new();
+        for (client_id, after) in other.all_summaries() {
+            match before.remove(&client_id) {
+                Some(before) if before != after => {
+                    diffs.push(AccountDiff::Changed { before, after })
+                }
+                Some(_) => {}
+                None => diffs.push(AccountDiff::Added(after)),
+            }
+        }
+        diffs.extend(before.into_values().map(AccountDiff::Removed));
+        diffs.sort_by_key(|x| match x {
+            AccountDiff::Added(summary)
+            | AccountDiff::Removed(summary)
+            | AccountDiff::Changed { after: summary, .. } => summary.client_id,
+        });
+        diffs
+    }
 
-            writeln!(writer, "{client},{available},{held},{total},{locked}")?;
+    fn all_summaries(&self) -> BTreeMap<ClientId, AccountSummary> {
+        self.store
+            .iter()
+            .map(|account_mutex| {
+                let account = self.lock_account(&account_mutex);
+                (account.client_id, account.summary())
+            })
+            .collect()
+    }
+
+    /// Add (or replace) accounts, e.g. from a snapshot.
+    pub fn restore(&self, accounts: Vec<Account>) {
+        for account in accounts {
+            self.store.insert(account);
         }
+    }
+
+    /// Sorted list of accounts that were recovered from a poisoned lock.
+    pub fn recovered_accounts(&self) -> Vec<ClientId> {
+        let mut ids: Vec<ClientId> = self
+            .recovered
+            .lock()
+            .expect("lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Sorted snapshot of all current client ids.
+    ///
+    /// No account is locked, so this doesn't wait for accounts in use.
+    pub fn client_ids(&self) -> Vec<ClientId> {
+        let mut ids = self.store.keys();
+        ids.sort();
+        ids
+    }
+
+    /// Every deposit across all accounts as `(client, tx, amount)`, sorted
+    /// by client and then transaction id, for reconciling against the
+    /// source feed.
+    pub fn deposits(&self) -> Vec<(ClientId, TransactionId, Currency)> {
+        let mut deposits = Vec::new();
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            deposits.extend(
+                account
+                    .transactions
+                    .values()
+                    .filter(|x| x.transaction_type == TransactionType::Deposit)
+                    .map(|x| (account.client_id, x.transaction_id, x.amount)),
+            );
+        }
+        deposits.sort_by_key(|(client_id, transaction_id, _)| (*client_id, *transaction_id));
+        deposits
+    }
+
+    /// Every open dispute as `(client, tx, held amount)`, sorted by client
+    /// and then transaction id.
+    pub fn all_open_disputes(&self) -> Vec<(ClientId, TransactionId, Currency)> {
+        let mut disputes = Vec::new();
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            disputes.extend(
+                account
+                    .disputes
+                    .iter()
+                    .map(|(id, hold)| (account.client_id, *id, *hold)),
+            );
+        }
+        disputes.sort_by_key(|(client_id, transaction_id, _)| (*client_id, *transaction_id));
+        disputes
+    }
+
+    /// Clients whose available balance is negative, sorted by client id.
+    ///
+    /// This can happen when a deposit is disputed after some of it has been
+    /// withdrawn. Unlike `check_invariants`, this only reports the clients.
+    pub fn clients_with_negative_available(&self) -> Vec<ClientId> {
+        let mut ids: Vec<ClientId> = self
+            .store
+            .iter()
+            .filter_map(|account_mutex| {
+                let account = self.lock_account(&account_mutex);
+                (account.available < Currency::ZERO).then_some(account.client_id)
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Auto-resolve any dispute raised more than `max_age` ago (according to
+    /// the database clock), returning the disputes that were resolved.
+    ///
+    /// Each one is resolved by applying a synthetic resolve transaction, so
+    /// the history still replays to the same balances. Disputes without a
+    /// timestamp never expire.
+    pub fn expire_disputes(&self, max_age: TimeDelta) -> Vec<(ClientId, TransactionId)> {
+        let now = self.now();
+        let mut expired = Vec::new();
+        for account_mutex in self.store.iter() {
+            let mut account = self.lock_account(&account_mutex);
+            let mut stale: Vec<TransactionId> = account
+                .disputes
+                .keys()
+                .copied()
+                .filter(|id| {
+                    account
+                        .dispute_time(*id)
+                        .is_some_and(|raised| now - raised > max_age)
+                })
+                .collect();
+            stale.sort();
+
+            for transaction_id in stale {
+                let resolve = Transaction::builder()
+                    .transaction_type(TransactionType::Resolve)
+                    .client_id(account.client_id)
+                    .transaction_id(transaction_id)
+                    .reference("auto-resolved: dispute expired")
+                    .timestamp(now)
+                    .build()
+                    .expect("a resolve has no amount");
+                match apply_transaction(resolve, &mut account) {
+                    Ok(()) => expired.push((account.client_id, transaction_id)),
+                    Err(e) => tracing::error!(
+                        "failed to expire dispute {transaction_id} for client {}: {e:#}",
+                        account.client_id
+                    ),
+                }
+            }
+        }
+        expired.sort();
+        expired
+    }
+
+    /// Sum the balances of every account.
+    ///
+    /// Returns an error rather than panicking if any sum overflows.
+    pub fn totals(&self) -> anyhow::Result<Totals> {
+        let mut totals = Totals::default();
+        for account_mutex in self.store.iter() {
+            let summary = self.lock_account(&account_mutex).summary();
+            totals.accounts += 1;
+            if summary.locked {
+                totals.locked_accounts += 1;
+            }
+            totals.available = totals
+                .available
+                .checked_add(summary.available)
+                .context("overflow summing available balances")?;
+            totals.held = totals
+                .held
+                .checked_add(summary.held)
+                .context("overflow summing held balances")?;
+            totals.total = totals
+                .total
+                .checked_add(summary.total)
+                .context("overflow summing total balances")?;
+        }
+        Ok(totals)
+    }
+
+    /// A rough estimate of the memory used by the accounts, in bytes, for
+    /// capacity planning.
+    ///
+    /// Each account counts its own size, a slot in the store's map and the
+    /// `Arc<Mutex<_>>` around it, plus the capacity of its history,
+    /// transaction cache and dispute set (and those of any compacted
+    /// summary) times the size of their entries, plus the length of any
+    /// references. Allocator overhead and hash table load factors are
+    /// ignored, so the real figure will be somewhat higher.
+    pub fn estimated_memory(&self) -> usize {
+        // The map entry (key, value and control byte), then the Arc's
+        // reference counts and the mutex itself.
+        let per_account = size_of::<ClientId>()
+            + size_of::<Arc<Mutex<Account>>>()
+            + 1
+            + 2 * size_of::<usize>()
+            + size_of::<Mutex<()>>();
+        self.store
+            .iter()
+            .map(|account_mutex| per_account + self.lock_account(&account_mutex).estimated_memory())
+            .sum()
+    }
+
+    /// The sum of held funds across all accounts, i.e. how much is currently
+    /// frozen in disputes.
+    pub fn total_held(&self) -> anyhow::Result<Currency> {
+        let mut held = Currency::ZERO;
+        for account_mutex in self.store.iter() {
+            held = held
+                .checked_add(self.lock_account(&account_mutex).held)
+                .context("overflow summing held balances")?;
+        }
+        Ok(held)
+    }
+
+    /// Check that no money has been created or lost: the sum of all account
+    /// totals must equal the deposits minus the withdrawals and chargebacks
+    /// recorded in the histories (plus any compacted totals).
+    pub fn conservation_check(&self) -> anyhow::Result<()> {
+        let overflow = || anyhow::anyhow!("overflow computing expected total");
+        let mut expected = Currency::ZERO;
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            if let Some(summary) = &account.compacted {
+                expected = expected.checked_add(summary.total).ok_or_else(overflow)?;
+            }
+            for transaction in &account.history {
+                expected = match transaction.transaction_type {
+                    TransactionType::Deposit | TransactionType::Adjustment => {
+                        expected.checked_add(transaction.amount)
+                    }
+                    TransactionType::Withdrawal => expected.checked_sub(transaction.amount),
+                    TransactionType::Chargeback if transaction.amount != Currency::ZERO => {
+                        expected.checked_sub(transaction.amount)
+                    }
+                    TransactionType::Chargeback => {
+                        let disputed = account
+                            .transactions
+                            .get(&transaction.transaction_id)
+                            .with_context(|| {
+                                format!(
+                                    "charged back tx {} not found for client {}",
+                                    transaction.transaction_id, account.client_id
+                                )
+                            })?;
+                        expected.checked_sub(disputed.amount)
+                    }
+                    TransactionType::Reversal => {
+                        let reversed = account
+                            .transactions
+                            .get(&transaction.transaction_id)
+                            .with_context(|| {
+                                format!(
+                                    "reversed tx {} not found for client {}",
+                                    transaction.transaction_id, account.client_id
+                                )
+                            })?;
+                        match reversed.transaction_type {
+                            TransactionType::Deposit => expected.checked_sub(transaction.amount),
+                            _ => expected.checked_add(transaction.amount),
+                        }
+                    }
+                    TransactionType::Dispute | TransactionType::Resolve => Some(expected),
+                }
+                .ok_or_else(overflow)?;
+            }
+        }
+
+        let actual = self.totals()?.total;
+        if actual != expected {
+            return Err(anyhow::anyhow!(
+                "money not conserved: accounts total {actual} but history implies {expected}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Repair every account whose cached balances or locked state don't
+    /// match its history, using `Account::rebuild_cache`. Returns the (sorted)
+    /// clients that were out of sync.
+    ///
+    /// Unlike `check_invariants`, this fixes the accounts rather than
+    /// reporting an error.
+    pub fn reconcile(&self) -> Vec<ClientId> {
+        let mut repaired = Vec::new();
+        for account_mutex in self.store.iter() {
+            let mut account = self.lock_account(&account_mutex);
+            let before = account.summary();
+            account.rebuild_cache();
+            if account.summary() != before {
+                tracing::warn!(
+                    "repaired client {}: {before:?} -> {:?}",
+                    account.client_id,
+                    account.summary()
+                );
+                repaired.push(account.client_id);
+            }
+        }
+        repaired.sort();
+        repaired
+    }
+
+    /// Run `Account::check_invariants` over every account.
+    pub fn check_invariants(&self) -> anyhow::Result<()> {
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            account
+                .check_invariants()
+                .with_context(|| format!("account {} is inconsistent", account.client_id))?;
+        }
+        Ok(())
+    }
+
+    pub fn output_data<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        self.output_data_with(writer, &OutputOptions::default())
+    }
+
+    /// Same as `output_data`, formatted according to `options`.
+    pub fn output_data_with<W: Write>(
+        &self,
+        mut writer: W,
+        options: &OutputOptions,
+    ) -> anyhow::Result<()> {
+        writeln!(writer, "{}", Self::output_header(options))?;
+        let mut row = Vec::with_capacity(options.columns.len());
+        let mut inactive = 0;
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            if !account.has_activity() {
+                inactive += 1;
+                if options.omit_inactive {
+                    continue;
+                }
+            }
+            self.output_row(&account, options, &mut row);
+            writeln!(writer, "{}", row.join(","))?;
+        }
+        writer.flush()?;
+        match (inactive, options.omit_inactive) {
+            (0, _) => {}
+            (_, true) => tracing::debug!("omitted {inactive} account(s) without activity"),
+            (_, false) => tracing::warn!(
+                "output includes {inactive} account(s) without activity, \
+                 created by transactions that failed"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Write the accounts (with default options) to `part-0.csv` through
+    /// `part-<shards - 1>.csv` in `dir`, split by `client_id % shards`.
+    ///
+    /// Every file gets a header, even if no clients land in it.
+    pub fn output_sharded(&self, dir: impl AsRef<Path>, shards: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(shards > 0, "number of shards must be at least 1");
+        let options = OutputOptions::default();
+        let dir = dir.as_ref();
+
+        let mut writers = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let path = dir.join(format!("part-{shard}.csv"));
+            let file = File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            writeln!(writer, "{}", Self::output_header(&options))?;
+            writers.push(writer);
+        }
+
+        let mut row = Vec::with_capacity(options.columns.len());
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            self.output_row(&account, &options, &mut row);
+            writeln!(
+                writers[account.client_id.shard(shards)],
+                "{}",
+                row.join(",")
+            )?;
+        }
+        for mut writer in writers {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn output_header(options: &OutputOptions) -> String {
+        let header: Vec<String> = options.columns.iter().map(|x| x.to_string()).collect();
+        header.join(",")
+    }
+
+    /// Fill `row` with the output fields for `account`.
+    fn output_row(&self, account: &Account, options: &OutputOptions, row: &mut Vec<String>) {
+        let format = |amount: Currency| match options.fixed_decimals {
+            true => amount.to_fixed_string(self.precision),
+            false => amount.to_string_with_precision(self.precision),
+        };
+        let activity = match options.columns.iter().any(OutputColumn::needs_activity) {
+            true => account.activity(),
+            false => AccountActivity::default(),
+        };
+
+        row.clear();
+        row.extend(options.columns.iter().map(|column| {
+            match column {
+                OutputColumn::Client => account.client_id.to_string(),
+                OutputColumn::Available => format(account.available),
+                OutputColumn::Held => format(account.held),
+                OutputColumn::Total => format(account.total),
+                OutputColumn::Locked => options
+                    .locked_format
+                    .format(account.is_locked())
+                    .to_string(),
+                OutputColumn::Deposited => format(activity.deposited),
+                OutputColumn::Withdrawn => format(activity.withdrawn),
+                OutputColumn::Disputes => activity.disputes.to_string(),
+                OutputColumn::HeldRatio => format(account.held_ratio()),
+                OutputColumn::Name => match options.aliases.get(&account.client_id) {
+                    Some(name) => quote_name(name),
+                    None => account.client_id.to_string(),
+                },
+            }
+        }));
+    }
+
+    /// Write the transaction history for a single client as CSV, with the
+    /// balances after each transaction.
+    ///
+    /// Balances are computed by replaying the history into a new account,
+    /// starting from the compacted summary if there is one.
+    pub fn statement<W: Write>(&self, client_id: ClientId, mut writer: W) -> anyhow::Result<()> {
+        let (mut account, history) = {
+            let account_mutex = self
+                .get_account(client_id)
+                .with_context(|| format!("unknown client: {client_id}"))?;
+            let account = self.lock_account(&account_mutex);
+            (account.replay_start(), account.history.clone())
+        };
+
+        writeln!(writer, "type,tx,amount,available,held,total")?;
+        for transaction in history {
+            let (ty, tx) = (transaction.transaction_type, transaction.transaction_id);
+            let amount = transaction.amount.to_string_with_precision(self.precision);
+            apply_transaction(transaction, &mut account)
+                .with_context(|| format!("failed to replay tx {tx}"))?;
+            let available = account.available.to_string_with_precision(self.precision);
+            let held = account.held.to_string_with_precision(self.precision);
+            let total = account.total.to_string_with_precision(self.precision);
+
+            writeln!(writer, "{ty},{tx},{amount},{available},{held},{total}")?;
+        }
+        writer.flush()?;
         Ok(())
     }
+
+    /// Same as `output_data` but buffers writes, for large outputs.
+    pub fn output_data_buffered<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        self.output_data_buffered_with(writer, &OutputOptions::default())
+    }
+
+    /// Same as `output_data_with` but buffers writes, for large outputs.
+    pub fn output_data_buffered_with<W: Write>(
+        &self,
+        writer: W,
+        options: &OutputOptions,
+    ) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        self.output_data_with(&mut writer, options)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The widest value that `output_row` can write in a column, in
+    /// characters, when amounts have fixed decimals.
+    fn column_width(&self, column: &OutputColumn, options: &OutputOptions) -> usize {
+        let client_width = u16::MAX.to_string().len();
+        match column {
+            OutputColumn::Client => client_width,
+            OutputColumn::Available
+            | OutputColumn::Held
+            | OutputColumn::Total
+            | OutputColumn::Deposited
+            | OutputColumn::Withdrawn
+            | OutputColumn::HeldRatio => Currency::from_decimal(Decimal::MIN)
+                .to_fixed_string(self.precision)
+                .len(),
+            OutputColumn::Locked => {
+                let format = &options.locked_format;
+                format.format(true).len().max(format.format(false).len())
+            }
+            OutputColumn::Disputes => usize::MAX.to_string().len(),
+            OutputColumn::Name => options
+                .aliases
+                .values()
+                .map(|name| quote_name(name).chars().count())
+                .fold(client_width, usize::max),
+        }
+    }
+
+    /// Write the accounts as a right-aligned, fixed-width table for reading
+    /// in a terminal, rather than as CSV.
+    ///
+    /// Amounts always show every decimal place, so that they line up on the
+    /// decimal point. Each column is as wide as the widest value it could
+    /// hold, so rows are written one at a time without a first pass over the
+    /// accounts.
+    pub fn output_table<W: Write>(&self, writer: W, options: &OutputOptions) -> anyhow::Result<()> {
+        let options = OutputOptions {
+            fixed_decimals: true,
+            ..options.clone()
+        };
+        let header: Vec<String> = options.columns.iter().map(|x| x.to_string()).collect();
+        let widths: Vec<usize> = options
+            .columns
+            .iter()
+            .zip(&header)
+            .map(|(column, name)| name.len().max(self.column_width(column, &options)))
+            .collect();
+
+        let mut writer = BufWriter::new(writer);
+        let write_row = |writer: &mut BufWriter<W>, row: &[String]| -> std::io::Result<()> {
+            let padded: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(field, width)| format!("{field:>width$}"))
+                .collect();
+            writeln!(writer, "{}", padded.join("  "))
+        };
+        write_row(&mut writer, &header)?;
+        let mut row = Vec::with_capacity(options.columns.len());
+        for account_mutex in self.store.iter() {
+            let account = self.lock_account(&account_mutex);
+            if options.omit_inactive && !account.has_activity() {
+                continue;
+            }
+            self.output_row(&account, &options, &mut row);
+            write_row(&mut writer, &row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Quote a display name for CSV if it needs it.
+fn quote_name(name: &str) -> String {
+    match name.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", name.replace('"', "\"\"")),
+        false => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn transaction(
+        transaction_type: TransactionType,
+        client_id: u16,
+        transaction_id: u32,
+        amount: f64,
+    ) -> Transaction {
+        Transaction::builder()
+            .transaction_type(transaction_type)
+            .client_id(ClientId::from(client_id))
+            .transaction_id(TransactionId::from(transaction_id))
+            .amount(Currency::from_f64(amount))
+            .build()
+            .unwrap()
+    }
+
+    /// Writer that records what was written and how many times it was flushed.
+    #[derive(Default)]
+    struct FlushTracker {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for FlushTracker {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let deposit =
+            |client, id, amount| transaction(TransactionType::Deposit, client, id, amount);
+        let database = AccountDatabase::default();
+        for (client, id) in [(1, 1), (2, 2), (3, 3)] {
+            database.apply(deposit(client, id, 10.0)).unwrap();
+        }
+
+        let copy = AccountDatabase::default();
+        copy.restore(database.snapshot());
+        assert!(database.diff(&copy).is_empty());
+
+        copy.apply(deposit(2, 4, 5.0)).unwrap();
+        copy.apply(deposit(4, 5, 1.0)).unwrap();
+        database.apply(deposit(5, 6, 2.0)).unwrap();
+
+        let summary = |client: u16, amount: f64| AccountSummary {
+            client_id: ClientId::from(client),
+            available: Currency::from_f64(amount),
+            held: Currency::ZERO,
+            total: Currency::from_f64(amount),
+            locked: false,
+        };
+        assert_eq!(
+            database.diff(&copy),
+            vec![
+                AccountDiff::Changed {
+                    before: summary(2, 10.0),
+                    after: summary(2, 15.0),
+                },
+                AccountDiff::Added(summary(4, 1.0)),
+                AccountDiff::Removed(summary(5, 2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_sharded() {
+        let database = AccountDatabase::default();
+        for (client, amount) in [(1, 1.0), (2, 2.0), (4, 4.0)] {
+            let deposit = transaction(TransactionType::Deposit, client, client.into(), amount);
+            database.apply(deposit).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        database.output_sharded(dir.path(), 3).unwrap();
+
+        let read = |shard: usize| {
+            let data =
+                std::fs::read_to_string(dir.path().join(format!("part-{shard}.csv"))).unwrap();
+            let mut lines: Vec<String> = data.lines().map(String::from).collect();
+            lines[1..].sort();
+            lines
+        };
+        let header = "client,available,held,total,locked";
+        assert_eq!(read(0), vec![header]);
+        assert_eq!(read(1), vec![header, "1,1,0,1,false", "4,4,0,4,false"]);
+        assert_eq!(read(2), vec![header, "2,2,0,2,false"]);
+
+        assert!(database.output_sharded(dir.path(), 0).is_err());
+    }
+
+    #[test]
+    fn test_expire_disputes() {
+        let now = DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let clock = Arc::new(MockClock::new(now - TimeDelta::days(2)));
+        let database = AccountDatabase::default().with_clock(clock.clone());
+        let account_mutex = database.account(ClientId::from(1));
+        {
+            let mut account = account_mutex.lock().unwrap();
+            for (transaction_type, id, age_days) in [
+                (TransactionType::Deposit, 1, 40),
+                (TransactionType::Deposit, 2, 40),
+                (TransactionType::Dispute, 1, 31),
+                (TransactionType::Dispute, 2, 1),
+            ] {
+                let transaction = Transaction::builder()
+                    .transaction_type(transaction_type)
+                    .client_id(ClientId::from(1))
+                    .transaction_id(TransactionId::from(id))
+                    .amount(match transaction_type {
+                        TransactionType::Deposit => Currency::from_f64(10.0),
+                        _ => Currency::default(),
+                    })
+                    .timestamp(now - TimeDelta::days(age_days))
+                    .build()
+                    .unwrap();
+                apply_transaction(transaction, &mut account).unwrap();
+            }
+        }
+
+        // Not old enough yet.
+        assert!(database.expire_disputes(TimeDelta::days(30)).is_empty());
+
+        clock.advance(TimeDelta::days(2));
+        let expired = database.expire_disputes(TimeDelta::days(30));
+        assert_eq!(expired, vec![(ClientId::from(1), TransactionId::from(1))]);
+
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.available, Currency::from_f64(10.0));
+        assert_eq!(account.held, Currency::from_f64(10.0));
+        assert!(!account.disputes.contains_key(&TransactionId::from(1)));
+        assert!(account.disputes.contains_key(&TransactionId::from(2)));
+        let last = account.history.last().unwrap();
+        assert_eq!(last.transaction_type, TransactionType::Resolve);
+        assert_eq!(last.timestamp, Some(now));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_held_must_match_open_disputes() {
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Deposit, 1, 2, 5.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+        ] {
+            apply_transaction(transaction, &mut account).unwrap();
+        }
+        account.check_invariants().unwrap();
+
+        // Still consistent with available and total, but not the disputes.
+        account.held = Currency::from_f64(7.0);
+        account.total = Currency::from_f64(17.0);
+        let err = account.check_invariants().expect_err("held was corrupted");
+        assert_eq!(
+            err.to_string(),
+            "held (7) does not equal the sum of open disputes (5)"
+        );
+    }
+
+    #[test]
+    fn test_rebuild_cache_restores_compacted_account() {
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Deposit, 1, 2, 5.0),
+            transaction(TransactionType::Withdrawal, 1, 3, 3.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            transaction(TransactionType::Deposit, 1, 4, 1.0),
+        ] {
+            apply_transaction(transaction, &mut account).unwrap();
+        }
+        account.compact(2);
+        let original = account.clone();
+
+        account.transactions.clear();
+        account.disputes.clear();
+        account.available = Currency::ZERO;
+        account.held = Currency::ZERO;
+        account.total = Currency::ZERO;
+        account.rebuild_cache();
+        assert_eq!(account, original);
+    }
+
+    #[test]
+    fn test_estimated_memory_grows_with_history() {
+        let database = AccountDatabase::default();
+        assert_eq!(database.estimated_memory(), 0);
+
+        let deposit = |client, tx| transaction(TransactionType::Deposit, client, tx, 1.0);
+        database.apply(deposit(1, 1)).unwrap();
+        let one = database.estimated_memory();
+        assert!(one > size_of::<Account>());
+
+        for tx in 2..100 {
+            database.apply(deposit(1, tx)).unwrap();
+        }
+        let many = database.estimated_memory();
+        assert!(
+            many > one + 98 * size_of::<Transaction>(),
+            "{one} -> {many}"
+        );
+
+        database.apply(deposit(2, 100)).unwrap();
+        assert!(database.estimated_memory() > many);
+    }
+
+    #[test]
+    fn test_reconcile_repairs_out_of_sync_account() {
+        let database = AccountDatabase::default();
+        for (client, tx, amount) in [(1, 1, 10.0), (2, 2, 5.0), (3, 3, 1.0)] {
+            database
+                .apply(transaction(TransactionType::Deposit, client, tx, amount))
+                .unwrap();
+        }
+        assert!(database.reconcile().is_empty());
+
+        {
+            let account_mutex = database.get_account(ClientId::from(2)).unwrap();
+            let mut account = account_mutex.lock().unwrap();
+            account.available = Currency::from_f64(50.0);
+            account.total = Currency::from_f64(50.0);
+        }
+        database
+            .check_invariants()
+            .expect_err("account 2 is out of sync");
+
+        assert_eq!(database.reconcile(), vec![ClientId::from(2)]);
+        let account_mutex = database.get_account(ClientId::from(2)).unwrap();
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(5.0)
+        );
+        database.verify_all_accounts();
+        assert!(database.reconcile().is_empty());
+    }
+
+    #[test]
+    fn test_transactions_of_type() {
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 3.0),
+            transaction(TransactionType::Deposit, 1, 3, 5.0),
+            transaction(TransactionType::Dispute, 1, 3, 0.0),
+            transaction(TransactionType::Withdrawal, 1, 4, 1.0),
+            transaction(TransactionType::Resolve, 1, 3, 0.0),
+        ] {
+            apply_transaction(transaction, &mut account).unwrap();
+        }
+
+        let ids = |transaction_type| -> Vec<TransactionId> {
+            account
+                .transactions_of_type(transaction_type)
+                .map(|x| x.transaction_id)
+                .collect()
+        };
+        assert_eq!(
+            ids(TransactionType::Withdrawal),
+            vec![TransactionId::from(2), TransactionId::from(4)]
+        );
+        assert_eq!(
+            ids(TransactionType::Deposit),
+            vec![TransactionId::from(1), TransactionId::from(3)]
+        );
+        assert_eq!(ids(TransactionType::Dispute), vec![TransactionId::from(3)]);
+        assert_eq!(ids(TransactionType::Chargeback), vec![]);
+    }
+
+    #[test]
+    fn test_totals_overflow() {
+        let database = AccountDatabase::default();
+        for client_id in [1, 2] {
+            let account_mutex = database.account(ClientId::from(client_id));
+            let mut account = account_mutex.lock().unwrap();
+            account.available = Currency::from_f64(5.0e28);
+            account.total = Currency::from_f64(5.0e28);
+        }
+
+        let err = database.totals().expect_err("totals should overflow");
+        assert!(err.to_string().contains("overflow"));
+
+        let database = AccountDatabase::default();
+        let account_mutex = database.account(ClientId::from(1));
+        account_mutex.lock().unwrap().total = Currency::from_f64(5.0e28);
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(5.0e28));
+    }
+
+    #[test]
+    fn test_client_id_serde() {
+        assert_eq!(serde_json::to_string(&ClientId::from(7)).unwrap(), r#""7""#);
+        let from_str: ClientId = serde_json::from_str(r#""7""#).unwrap();
+        let from_int: ClientId = serde_json::from_str("7").unwrap();
+        assert_eq!(from_str, ClientId::from(7));
+        assert_eq!(from_int, ClientId::from(7));
+
+        for input in [r#""abc""#, "70000", "-1"] {
+            let err = serde_json::from_str::<ClientId>(input).unwrap_err();
+            assert!(err.to_string().contains("invalid client id"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_to_stable_json() {
+        let run = || {
+            let database = AccountDatabase::default();
+            for id in 1..=50 {
+                let deposit =
+                    transaction(TransactionType::Deposit, (id % 3) as u16, id, f64::from(id));
+                database.apply(deposit).unwrap();
+            }
+            for id in (1..=50).step_by(4) {
+                let dispute = transaction(TransactionType::Dispute, (id % 3) as u16, id, 0.0);
+                database.apply(dispute).unwrap();
+            }
+            database.to_stable_json()
+        };
+
+        let json = run();
+        assert_eq!(json, run());
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["client_id"], "0");
+        assert_eq!(
+            value[0]["disputes"],
+            serde_json::json!({"9": "9", "21": "21", "33": "33", "45": "45"})
+        );
+        assert_eq!(value[0]["transactions"][0]["tx"], 3);
+    }
+
+    #[test]
+    fn test_audit_hash_detects_tampering() {
+        let build = || {
+            let mut account = Account::builder().client_id(ClientId::from(1)).build();
+            for transaction in [
+                transaction(TransactionType::Deposit, 1, 1, 10.0),
+                transaction(TransactionType::Deposit, 1, 2, 5.0),
+                transaction(TransactionType::Dispute, 1, 1, 0.0),
+            ] {
+                apply_transaction(transaction, &mut account).unwrap();
+            }
+            account
+        };
+
+        let mut account = build();
+        let hash = account.audit_hash();
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, build().audit_hash());
+        assert_eq!(hash, account.recompute_audit_hash());
+        assert_ne!(
+            hash,
+            Account::builder()
+                .client_id(ClientId::from(1))
+                .build()
+                .audit_hash()
+        );
+
+        // Compaction keeps the chain intact.
+        account.compact(1);
+        assert_eq!(account.audit_hash(), hash);
+        assert_eq!(account.recompute_audit_hash(), hash);
+        account.sanity_check();
+
+        let mut tampered = build();
+        tampered.history[1].amount = Currency::from_f64(50.0);
+        assert_ne!(tampered.recompute_audit_hash(), tampered.audit_hash());
+        assert_eq!(tampered.audit_hash(), hash);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut account = Account::builder().client_id(ClientId::from(7)).build();
+        for (transaction_type, id, amount) in [
+            (TransactionType::Deposit, 1, 10.123456),
+            (TransactionType::Deposit, 2, 5.0),
+            (TransactionType::Dispute, 1, 0.0),
+            (TransactionType::Resolve, 1, 0.0),
+            (TransactionType::Dispute, 2, 0.0),
+        ] {
+            let transaction = Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(7))
+                .transaction_id(TransactionId::from(id))
+                .amount(Currency::from_f64(amount))
+                .reference(format!("ref-{id}"))
+                .build()
+                .unwrap();
+            apply_transaction(transaction, &mut account).unwrap();
+        }
+
+        let json = account.to_json();
+        let restored = Account::from_json(&json).unwrap();
+        assert_eq!(restored, account);
+        assert_eq!(restored.history.len(), 5);
+        assert_eq!(restored.transactions.len(), 2);
+        assert_eq!(
+            restored.disputes,
+            HashMap::from([(TransactionId::from(2), Currency::from_f64(5.0))])
+        );
+        restored.sanity_check();
+
+        Account::from_json("{}").expect_err("incomplete JSON should be rejected");
+    }
+
+    #[test]
+    fn test_output_data_flushes() {
+        let database = AccountDatabase::default();
+        database.account(ClientId::from(1));
+
+        let mut writer = FlushTracker::default();
+        database.output_data(&mut writer).unwrap();
+        assert_eq!(writer.flushes, 1);
+
+        let mut buffered = FlushTracker::default();
+        database.output_data_buffered(&mut buffered).unwrap();
+        assert!(buffered.flushes > 0);
+        assert_eq!(buffered.data, writer.data);
+        assert_eq!(
+            String::from_utf8(buffered.data).unwrap(),
+            "client,available,held,total,locked\n1,0,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn test_dispute_can_make_available_negative() {
+        let database = AccountDatabase::default();
+        for client in [1, 2] {
+            let account_mutex = database.account(ClientId::from(client));
+            let mut account = account_mutex.lock().unwrap();
+            for transaction in [
+                transaction(TransactionType::Deposit, client, 1, 10.0),
+                transaction(TransactionType::Withdrawal, client, 2, 8.0),
+            ] {
+                apply_transaction(transaction, &mut account).unwrap();
+            }
+        }
+        assert!(database.clients_with_negative_available().is_empty());
+
+        // Disputing the deposit holds more than is still available.
+        let dispute = transaction(TransactionType::Dispute, 2, 1, 0.0);
+        apply_transaction(
+            dispute,
+            &mut database.account(ClientId::from(2)).lock().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            database.clients_with_negative_available(),
+            vec![ClientId::from(2)]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let database = AccountDatabase::default();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, 5.0);
+        database.apply(deposit.clone()).unwrap();
+        assert_eq!(
+            database.apply(deposit),
+            Err(TransactionError::DuplicateTransaction {
+                original: TransactionType::Deposit
+            })
+        );
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(5.0)
+        );
+
+        // A dispute for an unknown client does not create an account.
+        let dispute = transaction(TransactionType::Dispute, 2, 1, 0.0);
+        assert!(matches!(
+            database.apply(dispute),
+            Err(TransactionError::TransactionNotFound { .. })
+        ));
+        assert!(database.get_account(ClientId::from(2)).is_none());
+    }
+
+    #[test]
+    fn test_deposits_sorted_by_client_and_id() {
+        let database = AccountDatabase::default();
+        for transaction in [
+            transaction(TransactionType::Deposit, 2, 3, 5.0),
+            transaction(TransactionType::Deposit, 1, 2, 1.5),
+            transaction(TransactionType::Deposit, 1, 1, 2.0),
+            transaction(TransactionType::Withdrawal, 1, 4, 1.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(
+            account_mutex.lock().unwrap().deposit_ids(),
+            vec![TransactionId::from(1), TransactionId::from(2)]
+        );
+        assert_eq!(
+            database.deposits(),
+            vec![
+                (
+                    ClientId::from(1),
+                    TransactionId::from(1),
+                    Currency::from_f64(2.0)
+                ),
+                (
+                    ClientId::from(1),
+                    TransactionId::from(2),
+                    Currency::from_f64(1.5)
+                ),
+                (
+                    ClientId::from(2),
+                    TransactionId::from(3),
+                    Currency::from_f64(5.0)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fee_account() {
+        let fee_account = ClientId::from(99);
+        let database = AccountDatabase::default().with_fee_account(fee_account);
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("1.5".parse().unwrap())
+            .build();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 2.0),
+        ] {
+            database.apply_with(transaction, &options).unwrap();
+        }
+
+        let client = database.account(ClientId::from(1));
+        assert_eq!(client.lock().unwrap().available, Currency::from_f64(6.5));
+        let fees = database.account(fee_account);
+        assert_eq!(fees.lock().unwrap().available, Currency::from_f64(1.5));
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(8.0));
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_fee_without_fee_account() {
+        let database = AccountDatabase::default();
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("1.5".parse().unwrap())
+            .build();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 2.0),
+        ] {
+            database.apply_with(transaction, &options).unwrap();
+        }
+
+        // The fee is withdrawn with the amount, and credited nowhere.
+        let client = database.account(ClientId::from(1));
+        assert_eq!(client.lock().unwrap().available, Currency::from_f64(6.5));
+        assert_eq!(database.client_ids(), vec![ClientId::from(1)]);
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(6.5));
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_fee_account_locked() {
+        let fee_account = ClientId::from(99);
+        let database = AccountDatabase::default().with_fee_account(fee_account);
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("1.5".parse().unwrap())
+            .build();
+        database.account(fee_account).lock().unwrap().freeze();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, 10.0);
+        database.apply_with(deposit, &options).unwrap();
+
+        let withdrawal = transaction(TransactionType::Withdrawal, 1, 2, 2.0);
+        assert_eq!(
+            database.apply_with(withdrawal, &options),
+            Err(TransactionError::FeeAccountLocked)
+        );
+        let client = database.account(ClientId::from(1));
+        assert_eq!(client.lock().unwrap().available, Currency::from_f64(10.0));
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(10.0));
+    }
+
+    #[test]
+    fn test_fee_transaction_ids() {
+        let fee_account = ClientId::from(99);
+        let database = AccountDatabase::default().with_fee_account(fee_account);
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("1.0".parse().unwrap())
+            .build();
+        // The fee account has its own deposit with the same id as the
+        // withdrawal, and also pays a fee itself.
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Deposit, 99, 2, 10.0),
+            transaction(TransactionType::Withdrawal, 1, 2, 2.0),
+            transaction(TransactionType::Withdrawal, 99, 3, 4.0),
+        ] {
+            database.apply_with(transaction, &options).unwrap();
+        }
+
+        let client = database.account(ClientId::from(1));
+        assert_eq!(client.lock().unwrap().available, Currency::from_f64(7.0));
+        // 10 deposited, 1 fee from client 1, 4 withdrawn, fee paid to itself.
+        let fees = database.account(fee_account);
+        assert_eq!(fees.lock().unwrap().available, Currency::from_f64(7.0));
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(14.0));
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_total_held_matches_totals() {
+        let database = AccountDatabase::default();
+        assert_eq!(database.total_held().unwrap(), Currency::ZERO);
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 1.5),
+            transaction(TransactionType::Deposit, 1, 2, 2.0),
+            transaction(TransactionType::Deposit, 2, 3, 4.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Dispute, 2, 3, 0.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+
+        assert_eq!(database.total_held().unwrap(), Currency::from_f64(5.5));
+        assert_eq!(
+            database.total_held().unwrap(),
+            database.totals().unwrap().held
+        );
+    }
+
+    #[test]
+    fn test_open_disputes_exclude_closed() {
+        let database = AccountDatabase::default();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 1.0),
+            transaction(TransactionType::Deposit, 1, 2, 2.0),
+            transaction(TransactionType::Deposit, 1, 3, 3.0),
+            transaction(TransactionType::Deposit, 2, 4, 4.0),
+            transaction(TransactionType::Deposit, 3, 5, 5.0),
+            transaction(TransactionType::Dispute, 1, 1, 0.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            transaction(TransactionType::Dispute, 1, 3, 0.0),
+            transaction(TransactionType::Resolve, 1, 2, 0.0),
+            transaction(TransactionType::Dispute, 2, 4, 0.0),
+            transaction(TransactionType::Dispute, 3, 5, 0.0),
+            transaction(TransactionType::Chargeback, 3, 5, 0.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+
+        assert_eq!(
+            database.all_open_disputes(),
+            vec![
+                (
+                    ClientId::from(1),
+                    TransactionId::from(1),
+                    Currency::from_f64(1.0)
+                ),
+                (
+                    ClientId::from(1),
+                    TransactionId::from(3),
+                    Currency::from_f64(3.0)
+                ),
+                (
+                    ClientId::from(2),
+                    TransactionId::from(4),
+                    Currency::from_f64(4.0)
+                ),
+            ]
+        );
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, 10.0);
+        apply_transaction(deposit, &mut account).unwrap();
+
+        let withdrawal = transaction(TransactionType::Withdrawal, 1, 2, 4.0);
+        assert_eq!(
+            account.preview(&withdrawal),
+            Ok((
+                Currency::from_f64(6.0),
+                Currency::default(),
+                Currency::from_f64(6.0)
+            ))
+        );
+        assert_eq!(account.available, Currency::from_f64(10.0));
+        assert_eq!(account.history.len(), 1);
+
+        assert_eq!(
+            account.preview(&transaction(TransactionType::Withdrawal, 1, 3, 20.0)),
+            Err(TransactionError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_conservation_check_detects_created_money() {
+        let database = AccountDatabase::default();
+        database.conservation_check().unwrap();
+
+        let account_mutex = database.account(ClientId::from(1));
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 10.0),
+            transaction(TransactionType::Deposit, 1, 2, 5.0),
+            transaction(TransactionType::Withdrawal, 1, 3, 4.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            transaction(TransactionType::Chargeback, 1, 2, 0.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+        database.conservation_check().unwrap();
+
+        // Money appearing from nowhere is detected.
+        account_mutex.lock().unwrap().total += Currency::from_f64(1.0);
+        let err = database.conservation_check().expect_err("not conserved");
+        assert!(err.to_string().contains("money not conserved"));
+    }
+
+    #[test]
+    fn test_output_columns() {
+        let database = AccountDatabase::default();
+        database
+            .apply(transaction(TransactionType::Deposit, 1, 1, 2.5))
+            .unwrap();
+
+        let options = OutputOptions::builder()
+            .columns(vec![
+                OutputColumn::Client,
+                OutputColumn::Total,
+                OutputColumn::Locked,
+            ])
+            .build();
+        let mut output = Vec::new();
+        database.output_data_with(&mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,total,locked\n1,2.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_output_table() {
+        let database = AccountDatabase::with_precision(2);
+        for (client, tx, amount) in [(1, 1, "1234567.5"), (2, 2, "3.25")] {
+            let deposit = Transaction::builder()
+                .transaction_type(TransactionType::Deposit)
+                .client_id(ClientId::from(client))
+                .transaction_id(TransactionId::from(tx))
+                .amount(Currency::from_str(amount).unwrap())
+                .build()
+                .unwrap();
+            database.apply(deposit).unwrap();
+        }
+
+        let mut output = Vec::new();
+        database
+            .output_table(&mut output, &OutputOptions::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        // Wide enough for any amount: a sign, 29 digits and 2 decimals.
+        let row = |fields: [&str; 5]| {
+            format!(
+                "{:>6}  {:>33}  {:>33}  {:>33}  {:>6}",
+                fields[0], fields[1], fields[2], fields[3], fields[4]
+            )
+        };
+        assert_eq!(
+            lines.remove(0),
+            row(["client", "available", "held", "total", "locked"])
+        );
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                row(["1", "1234567.50", "0.00", "1234567.50", "false"]),
+                row(["2", "3.25", "0.00", "3.25", "false"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locked_column_formats() {
+        let database = AccountDatabase::default();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 1.0),
+            transaction(TransactionType::Deposit, 2, 2, 1.0),
+            transaction(TransactionType::Dispute, 2, 2, 0.0),
+            transaction(TransactionType::Chargeback, 2, 2, 0.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+
+        for (locked_format, unlocked, locked) in [
+            (LockedFormat::TrueFalse, "1,false", "2,true"),
+            (LockedFormat::OneZero, "1,0", "2,1"),
+            (LockedFormat::ActiveLocked, "1,active", "2,locked"),
+        ] {
+            let options = OutputOptions::builder()
+                .columns(vec![OutputColumn::Client, OutputColumn::Locked])
+                .locked_format(locked_format)
+                .build();
+            let mut output = Vec::new();
+            database.output_data_with(&mut output, &options).unwrap();
+            let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+            lines.sort();
+            assert_eq!(lines, vec![unlocked, locked, "client,locked"]);
+        }
+        assert_eq!(
+            OutputOptions::default().locked_format,
+            LockedFormat::TrueFalse
+        );
+        assert_eq!(
+            "active-locked".parse::<LockedFormat>().unwrap(),
+            LockedFormat::ActiveLocked
+        );
+    }
+
+    #[test]
+    fn test_held_ratio_of_total() {
+        let database = AccountDatabase::default();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 60.0),
+            transaction(TransactionType::Deposit, 1, 2, 40.0),
+            transaction(TransactionType::Dispute, 1, 2, 0.0),
+            transaction(TransactionType::Deposit, 2, 3, 5.0),
+            transaction(TransactionType::Withdrawal, 2, 4, 5.0),
+        ] {
+            database.apply(transaction).unwrap();
+        }
+
+        let options = OutputOptions::builder()
+            .columns(vec![OutputColumn::Client, OutputColumn::HeldRatio])
+            .build();
+        let mut output = Vec::new();
+        database.output_data_with(&mut output, &options).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(lines, vec!["1,0.4", "2,0", "client,held_ratio"]);
+    }
+
+    #[test]
+    fn test_name_column() {
+        let database = AccountDatabase::default();
+        for client in [1, 2, 3] {
+            let deposit = transaction(TransactionType::Deposit, client, client.into(), 1.0);
+            database.apply(deposit).unwrap();
+        }
+
+        let options = OutputOptions::builder()
+            .columns(vec![OutputColumn::Client, OutputColumn::Name])
+            .aliases(HashMap::from([
+                (ClientId::from(1), "Alice".to_string()),
+                (ClientId::from(3), "Smith, Bob".to_string()),
+            ]))
+            .build();
+        let mut output = Vec::new();
+        database.output_data_with(&mut output, &options).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec!["1,Alice", "2,2", "3,\"Smith, Bob\"", "client,name"]
+        );
+    }
+
+    #[test]
+    fn test_omit_inactive() {
+        let database = AccountDatabase::default();
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, 2.5),
+            // Fails, but only after creating the account.
+            transaction(TransactionType::Deposit, 2, 1, 0.0),
+            // Unknown client.
+            transaction(TransactionType::Dispute, 3, 1, 0.0),
+        ] {
+            database.apply(transaction).ok();
+        }
+
+        let output = |options: &OutputOptions| {
+            let mut output = Vec::new();
+            database.output_data_with(&mut output, options).unwrap();
+            let mut lines: Vec<String> = String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            output(&OutputOptions::default()),
+            vec![
+                "1,2.5,0,2.5,false",
+                "2,0,0,0,false",
+                "client,available,held,total,locked"
+            ]
+        );
+        assert_eq!(
+            output(&OutputOptions::builder().omit_inactive(true).build()),
+            vec!["1,2.5,0,2.5,false", "client,available,held,total,locked"]
+        );
+    }
+
+    #[test]
+    fn test_statement_shows_running_balances() {
+        let database = AccountDatabase::default();
+        let account_mutex = database.account(ClientId::from(1));
+        {
+            let mut account = account_mutex.lock().unwrap();
+            for transaction in [
+                transaction(TransactionType::Deposit, 1, 1, 10.0),
+                transaction(TransactionType::Deposit, 1, 2, 2.5),
+                transaction(TransactionType::Dispute, 1, 1, 0.0),
+                transaction(TransactionType::Resolve, 1, 1, 0.0),
+            ] {
+                apply_transaction(transaction, &mut account).unwrap();
+            }
+        }
+
+        let mut output = Vec::new();
+        database.statement(ClientId::from(1), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,tx,amount,available,held,total\n\
+             deposit,1,10,10,0,10\n\
+             deposit,2,2.5,12.5,0,12.5\n\
+             dispute,1,0,2.5,10,12.5\n\
+             resolve,1,0,12.5,0,12.5\n"
+        );
+
+        database
+            .statement(ClientId::from(2), Vec::new())
+            .expect_err("unknown client");
+    }
 }
 
 #[cfg(test)]
@@ -128,37 +2498,17 @@ pub mod test_support {
 
     use super::*;
 
-    use crate::{processor::apply_transaction, transaction::TransactionType};
-
     impl Account {
         pub fn sanity_check(&self) {
-            // Verify amounts.
-            assert_eq!(self.available, self.total - self.held);
-            // Account should only be locked if a chargeback occurred, and
-            // if so, the chargeback should be the last transaction.
-            assert_eq!(
-                self.history
-                    .iter()
-                    .last()
-                    .map(|x| x.transaction_type == TransactionType::Chargeback)
-                    .unwrap_or_default(),
-                self.is_locked()
-            );
-
-            let mut new_account = Account::builder().client_id(self.client_id).build();
-
-            for transaction in &self.history {
-                apply_transaction(transaction.clone(), &mut new_account).ok();
+            if let Err(e) = self.check_invariants() {
+                panic!("account {} failed sanity check: {e:#}", self.client_id);
             }
-
-            assert_eq!(self, &new_account);
         }
     }
 
     impl AccountDatabase {
         pub fn verify_all_accounts(&self) {
-            let data = self.data.read().unwrap();
-            for account_mutex in data.values() {
+            for account_mutex in self.store.iter() {
                 let account = account_mutex.lock().unwrap();
                 account.sanity_check();
             }
@@ -169,7 +2519,7 @@ pub mod test_support {
         type Parameters = ();
 
         fn arbitrary_with(_args: Self::Parameters) -> Self::Strategy {
-            (0..11u16).prop_map(|x| Self(x)).boxed()
+            (0..11u16).prop_map(Self).boxed()
         }
 
         type Strategy = BoxedStrategy<Self>;
@@ -0,0 +1,67 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{
+    account::{Account, AccountDatabase},
+    store::AccountStore,
+};
+
+/// The state of all accounts after a number of input records, so that a
+/// long run can be resumed from that point.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of input records processed before the snapshot was taken.
+    pub records: u64,
+    pub accounts: Vec<Account>,
+}
+
+impl Checkpoint {
+    /// Snapshot the database after `records` input records.
+    ///
+    /// The caller must ensure that exactly those records have been applied
+    /// (see `Processor::sync`).
+    pub fn new<S: AccountStore>(records: u64, database: &AccountDatabase<S>) -> Self {
+        Self {
+            records,
+            accounts: database.snapshot(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("failed to read checkpoint: {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("failed to parse checkpoint: {}", path.display()))
+    }
+
+    /// Write the checkpoint, replacing any previous one.
+    ///
+    /// It is written to a uniquely named temporary file in the same
+    /// directory first, so that an interrupted write never leaves a partial
+    /// checkpoint behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string(self).context("failed to serialize checkpoint")?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut temp_file = NamedTempFile::new_in(dir)
+            .with_context(|| format!("failed to write checkpoint: {}", path.display()))?;
+        temp_file
+            .write_all(json.as_bytes())
+            .with_context(|| format!("failed to write checkpoint: {}", path.display()))?;
+        temp_file
+            .persist(path)
+            .with_context(|| format!("failed to write checkpoint: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Load the accounts into the database.
+    pub fn restore<S: AccountStore>(self, database: &AccountDatabase<S>) {
+        database.restore(self.accounts);
+    }
+}
@@ -6,34 +6,114 @@ use std::{
 
 use anyhow::Context;
 use rust_decimal::Decimal;
-use serde_with::{DeserializeFromStr, SerializeDisplay};
-
-const DECIMAL_PLACES: u32 = 4;
-
-#[derive(
-    Debug,
-    Clone,
-    Copy,
-    Default,
-    PartialEq,
-    Eq,
-    Hash,
-    PartialOrd,
-    Ord,
-    SerializeDisplay,
-    DeserializeFromStr,
-)]
+use serde::Serialize;
+use serde_with::DeserializeFromStr;
+
+/// Number of decimal places used when displaying amounts.
+pub const DECIMAL_PLACES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, DeserializeFromStr)]
 pub struct Currency(Decimal);
 
+/// Serialized at full precision (unlike `Display`, which rounds) so that
+/// serialized data round-trips exactly.
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
 impl Currency {
+    pub const ZERO: Self = Self(Decimal::ZERO);
+
+    /// Wrap a `Decimal`, keeping its full precision.
+    pub fn from_decimal(value: Decimal) -> Self {
+        Self(value)
+    }
+
+    /// The underlying `Decimal`, at full precision.
+    pub fn as_decimal(&self) -> Decimal {
+        self.0
+    }
+
     pub fn is_negative(&self) -> bool {
         self.0 < Decimal::ZERO
     }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Addition that returns `None` on overflow.
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    /// Subtraction that returns `None` on overflow.
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    /// Addition that clamps to the largest or smallest value on overflow.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+
+    /// Subtraction that clamps to the largest or smallest value on overflow.
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Number of significant decimal places, ignoring trailing zeros
+    /// (so "1.10" has 1).
+    pub fn decimal_places(&self) -> u32 {
+        self.0.normalize().scale()
+    }
+
+    /// Format the value rounded to the specified number of decimal places.
+    pub fn to_string_with_precision(&self, decimal_places: u32) -> String {
+        self.0.round_dp(decimal_places).to_string()
+    }
+
+    /// Same as `to_string_with_precision`, but always shows exactly that
+    /// many decimal places (e.g. "1.5000" rather than "1.5").
+    pub fn to_fixed_string(&self, decimal_places: u32) -> String {
+        format!(
+            "{:.*}",
+            decimal_places as usize,
+            self.0.round_dp(decimal_places)
+        )
+    }
+
+    /// Format with thousands separators, for reports (e.g. "1,234,567.8900").
+    /// Uses the same rounding as `Display`, always showing all decimal places.
+    pub fn to_grouped_string(&self) -> String {
+        let fixed = self.to_fixed_string(DECIMAL_PLACES);
+        let (sign, unsigned) = match fixed.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", fixed.as_str()),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        let mut grouped = String::with_capacity(fixed.len() + integer.len() / 3);
+        grouped.push_str(sign);
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        if !fraction.is_empty() {
+            grouped.push('.');
+            grouped.push_str(fraction);
+        }
+        grouped
+    }
 }
 
 impl Display for Currency {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0.round_dp(DECIMAL_PLACES))
+        write!(f, "{}", self.to_string_with_precision(DECIMAL_PLACES))
     }
 }
 
@@ -41,9 +121,56 @@ impl FromStr for Currency {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(
-            Decimal::from_str(s).context("failed to parse currency value: {s}")?,
-        ))
+        // Monetary inputs should always be plain decimals.
+        if s.contains(['e', 'E']) {
+            return Err(anyhow::anyhow!(
+                "exponential notation not allowed in amount: {s}"
+            ));
+        }
+
+        Ok(Self(Decimal::from_str(s).with_context(|| {
+            format!("failed to parse currency value: {s}")
+        })?))
+    }
+}
+
+/// An amount that is known not to be negative.
+///
+/// Used for seed balances and configured amounts such as the withdrawal
+/// fee, so that negative values are rejected while parsing. Transaction
+/// amounts are checked by `Transaction::new` instead, since adjustments may
+/// be negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, DeserializeFromStr)]
+pub struct NonNegativeCurrency(Currency);
+
+impl NonNegativeCurrency {
+    pub fn get(&self) -> Currency {
+        self.0
+    }
+}
+
+impl TryFrom<Currency> for NonNegativeCurrency {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Currency) -> Result<Self, Self::Error> {
+        if value.is_negative() {
+            return Err(anyhow::anyhow!("amount must not be negative: {value}"));
+        }
+        Ok(Self(value))
+    }
+}
+
+impl From<NonNegativeCurrency> for Currency {
+    fn from(value: NonNegativeCurrency) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for NonNegativeCurrency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Currency::from_str(s)?.try_into()
     }
 }
 
@@ -83,11 +210,21 @@ pub mod test_support {
     use rust_decimal::prelude::FromPrimitive;
 
     impl Currency {
+        /// Convenient for tests, but lossy, so not available otherwise.
+        /// Use `from_decimal` or `FromStr` instead.
         pub fn from_f64(value: f64) -> Self {
-            Self(Decimal::from_f64(value).unwrap())
+            Self::from_decimal(Decimal::from_f64(value).unwrap())
         }
     }
 
+    /// Exact values with up to `DECIMAL_PLACES` decimal places, positive or
+    /// negative. Unlike `any::<Currency>()`, nothing is lost going through
+    /// f64, so these can be compared exactly after a round trip.
+    pub fn lossless_currency() -> impl Strategy<Value = Currency> {
+        (any::<i64>(), 0..=DECIMAL_PLACES)
+            .prop_map(|(mantissa, scale)| Currency(Decimal::new(mantissa, scale)))
+    }
+
     impl Arbitrary for Currency {
         type Parameters = ();
 
@@ -100,3 +237,118 @@ pub mod test_support {
         type Strategy = BoxedStrategy<Self>;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{test_support::lossless_currency, *};
+
+    proptest! {
+        #[test]
+        fn test_string_round_trip_proptest(currency in lossless_currency()) {
+            prop_assert_eq!(Currency::from_str(&currency.to_string()).unwrap(), currency);
+
+            let json = serde_json::to_string(&currency).unwrap();
+            prop_assert_eq!(serde_json::from_str::<Currency>(&json).unwrap(), currency);
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        let err = Currency::from_str("1e3").expect_err("exponential notation should be rejected");
+        assert!(err
+            .to_string()
+            .contains("exponential notation not allowed in amount"));
+        Currency::from_str("1E3").expect_err("exponential notation should be rejected");
+
+        assert_eq!(
+            Currency::from_str("+5.00").unwrap(),
+            Currency::from_f64(5.0)
+        );
+        assert_eq!(Currency::from_str("5.00").unwrap(), Currency::from_f64(5.0));
+    }
+
+    #[test]
+    fn test_decimal_round_trip() {
+        let value = Decimal::from_str("1234.56789").unwrap();
+        let currency = Currency::from_decimal(value);
+        assert_eq!(currency.as_decimal(), value);
+        // Full precision is kept, even though `Display` rounds.
+        assert_eq!(currency.to_string(), "1234.5679");
+        assert_eq!(Currency::from_decimal(Decimal::ZERO), Currency::ZERO);
+    }
+
+    #[test]
+    fn test_non_negative() {
+        let err = NonNegativeCurrency::from_str("-1.5").expect_err("negative should be rejected");
+        assert!(err.to_string().contains("amount must not be negative"));
+        assert_eq!(
+            NonNegativeCurrency::from_str("1.5").unwrap().get(),
+            Currency::from_f64(1.5)
+        );
+        assert_eq!(
+            NonNegativeCurrency::from_str("0").unwrap().get(),
+            Currency::default()
+        );
+    }
+
+    #[test]
+    fn test_to_fixed_string() {
+        assert_eq!(Currency::from_f64(1.5).to_fixed_string(4), "1.5000");
+        assert_eq!(Currency::from_f64(1.23456).to_fixed_string(4), "1.2346");
+        assert_eq!(Currency::default().to_fixed_string(4), "0.0000");
+        assert_eq!(Currency::from_f64(-2.0).to_fixed_string(2), "-2.00");
+    }
+
+    #[test]
+    fn test_decimal_places() {
+        assert_eq!(Currency::from_str("1.001").unwrap().decimal_places(), 3);
+        assert_eq!(Currency::from_str("1.10").unwrap().decimal_places(), 1);
+        assert_eq!(Currency::from_str("5").unwrap().decimal_places(), 0);
+    }
+
+    #[test]
+    fn test_to_grouped_string() {
+        let value = Currency::from_str("1234567.89").unwrap();
+        assert_eq!(value.to_grouped_string(), "1,234,567.8900");
+        assert_eq!(
+            Currency::from_str("-1234567.123456")
+                .unwrap()
+                .to_grouped_string(),
+            "-1,234,567.1235"
+        );
+        assert_eq!(
+            Currency::from_str("999").unwrap().to_grouped_string(),
+            "999.0000"
+        );
+        assert_eq!(
+            Currency::from_str("-100000").unwrap().to_grouped_string(),
+            "-100,000.0000"
+        );
+        assert_eq!(Currency::ZERO.to_grouped_string(), "0.0000");
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let max = Currency(Decimal::MAX);
+        let one = Currency::from_f64(1.0);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(Currency(Decimal::MIN).checked_sub(one), None);
+        assert_eq!(one.checked_add(one), Some(Currency::from_f64(2.0)));
+        assert_eq!(one.checked_sub(one), Some(Currency::default()));
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        let max = Currency(Decimal::MAX);
+        let min = Currency(Decimal::MIN);
+        let one = Currency::from_f64(1.0);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(min.saturating_sub(one), min);
+        assert_eq!(min.saturating_add(min), min);
+        assert_eq!(max.saturating_sub(min), max);
+        assert_eq!(max.saturating_sub(one), max - one);
+        assert_eq!(one.saturating_add(one), Currency::from_f64(2.0));
+    }
+}
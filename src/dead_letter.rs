@@ -0,0 +1,66 @@
+use std::{io::Write, sync::mpsc::Receiver};
+
+use csv::StringRecord;
+
+use crate::transaction::Transaction;
+
+/// Something that could not be processed, along with the reason it failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub entry: DeadLetterEntry,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeadLetterEntry {
+    /// A transaction that could not be applied.
+    Transaction(Transaction),
+    /// An input row that could not be parsed, exactly as it was read.
+    Row {
+        headers: StringRecord,
+        row: StringRecord,
+    },
+}
+
+impl DeadLetterEntry {
+    /// The values for `Transaction::CSV_COLUMNS`.
+    fn fields(&self) -> Vec<String> {
+        match self {
+            Self::Transaction(transaction) => transaction.csv_fields(),
+            Self::Row { headers, row } => Transaction::CSV_COLUMNS
+                .iter()
+                .map(|column| {
+                    headers
+                        .iter()
+                        .position(|x| x == *column)
+                        .and_then(|i| row.get(i))
+                        .unwrap_or_default()
+                        .to_string()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Write dead letters as CSV until the channel is closed, returning the
+/// number written.
+///
+/// The columns match the transaction input (plus a `reason` column), so the
+/// file can be fed back through `process_csv` to replay the failures.
+pub fn write_dead_letters<W: Write>(
+    dead_letters: Receiver<DeadLetter>,
+    writer: W,
+) -> anyhow::Result<usize> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(Transaction::CSV_COLUMNS.iter().chain(&["reason"]))?;
+
+    let mut count = 0;
+    for dead_letter in dead_letters {
+        let mut fields = dead_letter.entry.fields();
+        fields.push(dead_letter.reason);
+        writer.write_record(fields)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
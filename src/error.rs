@@ -0,0 +1,89 @@
+use thiserror::Error;
+
+use crate::{
+    account::ClientId,
+    currency::Currency,
+    transaction::{TransactionId, TransactionType},
+};
+
+/// Reasons a transaction can be rejected when applied to an account.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TransactionError {
+    #[error("account is locked")]
+    AccountLocked,
+    /// A withdrawal fee can't be credited, so the withdrawal is rejected.
+    #[error("fee account is locked")]
+    FeeAccountLocked,
+    /// Names the transaction, so that the offending feed entry can be found.
+    #[error(
+        "transaction amount must not be negative: {amount} (client {client_id}, tx \
+         {transaction_id})"
+    )]
+    NegativeAmount {
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Currency,
+    },
+    #[error("amount must be positive")]
+    ZeroAmount,
+    #[error("amount has more than {max} decimal places")]
+    TooPrecise { max: u32 },
+    /// `original` is the type of the existing transaction, since an id reused
+    /// for a different type usually points to a problem with the feed.
+    #[error("transaction id already exists (originally a {original})")]
+    DuplicateTransaction { original: TransactionType },
+    #[error("insufficient funds")]
+    InsufficientFunds,
+    #[error("daily withdrawal limit of {limit} exceeded")]
+    DailyLimitExceeded { limit: Currency },
+    /// Accounts are keyed by client, so a dispute naming the wrong client
+    /// also ends up here.
+    #[error(
+        "disputed transaction not found: tx {transaction_id} is not in the history of client \
+         {client_id} (it may belong to a different client)"
+    )]
+    TransactionNotFound {
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    },
+    #[error("transaction already disputed")]
+    AlreadyDisputed,
+    #[error("cannot dispute an unsettled transaction")]
+    Unsettled,
+    #[error("transaction not in dispute")]
+    NotDisputed,
+    #[error("chargeback of {amount} exceeds the disputed amount of {disputed}")]
+    ChargebackExceedsDispute {
+        amount: Currency,
+        disputed: Currency,
+    },
+    #[error("only the most recent transaction can be reversed")]
+    NotMostRecent,
+    #[error("transaction has been reversed")]
+    Reversed,
+    /// The account holds less than the dispute does, which means the account
+    /// is inconsistent.
+    #[error("held balance of {held} is less than the disputed amount of {disputed}")]
+    HeldBelowDispute { held: Currency, disputed: Currency },
+    #[error("cannot dispute an adjustment")]
+    AdjustmentDisputed,
+    /// Adjustments must say why they were made.
+    #[error("adjustment requires a reference")]
+    MissingReference,
+    /// Rejected by a custom validator.
+    #[error("transaction rejected: {0}")]
+    Rejected(String),
+}
+
+/// Returned by `Processor::send_transaction` once `max_failures`
+/// transactions have failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("stopped after {0} failed transactions")]
+pub struct FailureLimitReached(pub u64);
+
+/// Context attached to errors caused by malformed input, so that they can
+/// be told apart from other failures (e.g. for the exit code).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("failed to parse {0}")]
+pub struct ParseError(pub &'static str);
@@ -0,0 +1,96 @@
+use std::{io::Write, sync::mpsc::Receiver};
+
+use crate::transaction::Transaction;
+
+/// Output formats for the event log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum EventLogFormat {
+    /// The same columns as the transaction input.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+}
+
+/// Write applied transactions as they arrive until the channel is closed,
+/// returning the number written.
+///
+/// Transactions are written as they were received (before any fee or dust
+/// sweep was applied), so replaying the log with the same options
+/// reproduces the accounts. Each entry is flushed as it is written, so the
+/// log can be tailed.
+pub fn write_event_log<W: Write>(
+    applied: Receiver<Transaction>,
+    writer: W,
+    format: EventLogFormat,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    match format {
+        EventLogFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(Transaction::CSV_COLUMNS)?;
+            writer.flush()?;
+            for transaction in applied {
+                writer.write_record(transaction.csv_fields())?;
+                writer.flush()?;
+                count += 1;
+            }
+        }
+        EventLogFormat::Ndjson => {
+            let mut writer = writer;
+            for transaction in applied {
+                serde_json::to_writer(&mut writer, &transaction)?;
+                writeln!(writer)?;
+                writer.flush()?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{
+        account::ClientId,
+        currency::Currency,
+        transaction::{TransactionId, TransactionType},
+    };
+
+    #[test]
+    fn test_write_event_log() {
+        let deposit = Transaction::builder()
+            .transaction_type(TransactionType::Deposit)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(1))
+            .amount(Currency::from_f64(1.5))
+            .build()
+            .unwrap();
+
+        let (tx, rx) = channel();
+        tx.send(deposit.clone()).unwrap();
+        drop(tx);
+        let mut output = Vec::new();
+        assert_eq!(
+            write_event_log(rx, &mut output, EventLogFormat::Csv).unwrap(),
+            1
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,reference,timestamp\ndeposit,1,1,1.5,,\n"
+        );
+
+        let (tx, rx) = channel();
+        tx.send(deposit.clone()).unwrap();
+        drop(tx);
+        let mut output = Vec::new();
+        write_event_log(rx, &mut output, EventLogFormat::Ndjson).unwrap();
+        let line = String::from_utf8(output).unwrap();
+        let parsed: Transaction = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed, deposit);
+    }
+}
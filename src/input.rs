@@ -0,0 +1,1416 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::Context;
+use csv::{ReaderBuilder, StringRecord};
+use serde::Deserialize;
+
+use crate::{
+    account::{Account, AccountDatabase, ClientId},
+    checkpoint::Checkpoint,
+    currency::NonNegativeCurrency,
+    error::ParseError,
+    processor::Processor,
+    store::AccountStore,
+    transaction::{Transaction, TransactionRecord, TransactionType},
+};
+
+/// Supported input file formats.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// A TOML document with a `[[transactions]]` array.
+    Toml,
+}
+
+/// Which parts of a CSV file have leading and trailing whitespace removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum TrimMode {
+    None,
+    Headers,
+    Fields,
+    #[default]
+    All,
+}
+
+impl From<TrimMode> for csv::Trim {
+    fn from(mode: TrimMode) -> Self {
+        match mode {
+            TrimMode::None => csv::Trim::None,
+            TrimMode::Headers => csv::Trim::Headers,
+            TrimMode::Fields => csv::Trim::Fields,
+            TrimMode::All => csv::Trim::All,
+        }
+    }
+}
+
+/// Options controlling how input is read.
+#[non_exhaustive]
+#[derive(Debug, Clone, bon::Builder)]
+pub struct InputOptions {
+    #[builder(default)]
+    pub format: InputFormat,
+    /// Whitespace trimming for CSV input.
+    #[builder(default)]
+    pub trim: TrimMode,
+    /// Field delimiter for CSV input.
+    #[builder(default = b',')]
+    pub delimiter: u8,
+    /// Skip CSV rows that can't be parsed, sending them to the processor's
+    /// dead letters, instead of stopping with an error.
+    #[builder(default)]
+    pub skip_bad_rows: bool,
+    /// Stop after reading this many CSV records from each input.
+    pub limit: Option<u64>,
+    /// Memory-map files in `process_file` instead of reading them (requires
+    /// the `mmap` feature). The files must not be modified while they are
+    /// being processed.
+    #[builder(default)]
+    pub mmap: bool,
+    /// Other names for transaction types used by some feeds (e.g. "credit"
+    /// for deposit), matched exactly before the usual names.
+    #[builder(default)]
+    pub type_aliases: HashMap<String, TransactionType>,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Process transactions from a reader in the given format.
+pub fn process_reader<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    input: R,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    match options.format {
+        InputFormat::Csv => process_csv_with(processor, input, options),
+        InputFormat::Toml => process_toml(processor, input),
+    }
+}
+
+/// The UTF-8 byte order mark, as written by Excel and some other tools.
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Wrap the input in a buffered reader, skipping a leading UTF-8 BOM if present.
+///
+/// Otherwise the BOM ends up in the first header cell and no rows match.
+fn skip_bom<R: std::io::Read>(input: R) -> anyhow::Result<BufReader<R>> {
+    let mut reader = BufReader::new(input);
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    Ok(reader)
+}
+
+pub fn process_csv<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    input: R,
+) -> anyhow::Result<()> {
+    process_csv_with(processor, input, &InputOptions::default())
+}
+
+/// A completely empty input (not even a header) has no transactions,
+/// rather than being an error.
+fn is_empty<R: std::io::Read>(reader: &mut csv::Reader<R>) -> anyhow::Result<bool> {
+    let empty = reader.headers()?.is_empty();
+    if empty {
+        tracing::debug!("input is empty");
+    }
+    Ok(empty)
+}
+
+/// Build a CSV reader for transaction input.
+///
+/// The reader is flexible so that `read_record` can tolerate a trailing comma.
+fn csv_reader<R: std::io::Read>(
+    input: R,
+    options: &InputOptions,
+) -> anyhow::Result<csv::Reader<BufReader<R>>> {
+    Ok(ReaderBuilder::new()
+        .trim(options.trim.into())
+        .delimiter(options.delimiter)
+        .flexible(true)
+        .from_reader(skip_bom(input)?))
+}
+
+/// Read the next record, dropping a single trailing empty field (from a
+/// trailing comma). Any other mismatch with the header length is an error.
+fn read_record<R: std::io::Read>(
+    reader: &mut csv::Reader<R>,
+    record: &mut StringRecord,
+) -> anyhow::Result<bool> {
+    if !reader.read_record(record)? {
+        return Ok(false);
+    }
+    let expected = reader.headers()?.len();
+    let line = record.position().map(|x| x.line()).unwrap_or_default();
+    check_field_count(record, expected, line)?;
+    Ok(true)
+}
+
+/// See `read_record`.
+fn check_field_count(record: &mut StringRecord, expected: usize, line: u64) -> anyhow::Result<()> {
+    if record.len() == expected + 1 && record.get(expected) == Some("") {
+        record.truncate(expected);
+    }
+    if record.len() != expected {
+        return Err(anyhow::anyhow!(
+            "record on line {line} has {} fields, but the header has {expected}",
+            record.len()
+        ))
+        .context(ParseError("record from CSV"));
+    }
+    Ok(())
+}
+
+/// Process CSV from a stream that may still be being written (e.g. stdin
+/// fed by another process) one line at a time, until EOF, returning the
+/// number of records read.
+///
+/// Each transaction is sent as soon as its line is complete, so with
+/// `ProcessorConfig::event_log` every line can be seen in the log without
+/// waiting for more input. Unlike `process_csv_with`, quoted fields can't
+/// span lines.
+pub fn process_lines<R: BufRead, S: AccountStore>(
+    processor: &Processor<S>,
+    mut input: R,
+    options: &InputOptions,
+) -> anyhow::Result<u64> {
+    let trim_headers = matches!(options.trim, TrimMode::Headers | TrimMode::All);
+    let trim_fields = matches!(options.trim, TrimMode::Fields | TrimMode::All);
+    let limit = options.limit.unwrap_or(u64::MAX);
+    let mut headers = None;
+    let mut line = String::new();
+    let mut line_number = 0;
+    let mut records = 0;
+    while records < limit {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let text = line.trim_end_matches(['\r', '\n']);
+        let text = match line_number {
+            1 => text.strip_prefix('\u{feff}').unwrap_or(text),
+            _ => text,
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let Some(headers) = &headers else {
+            headers = Some(parse_line(text, options.delimiter, trim_headers)?);
+            continue;
+        };
+        let mut record = parse_line(text, options.delimiter, trim_fields)?;
+        check_field_count(&mut record, headers.len(), line_number)?;
+        records += 1;
+        if let Some(transaction) = parse_record(processor, headers, &record, options)? {
+            processor.send_transaction(transaction)?;
+        }
+    }
+    Ok(records)
+}
+
+/// Split a single line of CSV into fields.
+fn parse_line(line: &str, delimiter: u8, trim: bool) -> anyhow::Result<StringRecord> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .trim(match trim {
+            true => csv::Trim::All,
+            false => csv::Trim::None,
+        })
+        .from_reader(line.as_bytes());
+    let mut record = StringRecord::new();
+    reader
+        .read_record(&mut record)
+        .context(ParseError("record from CSV"))?;
+    Ok(record)
+}
+
+/// Same as `process_csv`, using the CSV settings from `options`.
+pub fn process_csv_with<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    input: R,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    let mut reader = csv_reader(input, options)?;
+    if is_empty(&mut reader)? {
+        return Ok(());
+    }
+    let headers = reader.headers()?.clone();
+    let limit = options.limit.unwrap_or(u64::MAX);
+    let mut record = StringRecord::new();
+    let mut records = 0;
+    while records < limit && read_record(&mut reader, &mut record)? {
+        records += 1;
+        if let Some(transaction) = parse_record(processor, &headers, &record, options)? {
+            processor.send_transaction(transaction)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deserialize a CSV record. With `skip_bad_rows`, a record that can't be
+/// parsed is passed to `Processor::skip_row` and `None` is returned.
+fn parse_record<S: AccountStore>(
+    processor: &Processor<S>,
+    headers: &StringRecord,
+    record: &StringRecord,
+    options: &InputOptions,
+) -> anyhow::Result<Option<Transaction>> {
+    let resolved = resolve_type_alias(headers, record, options);
+    let resolved = resolved.as_ref().unwrap_or(record);
+    match resolved.deserialize(Some(headers)) {
+        Ok(transaction) => Ok(Some(transaction)),
+        Err(e) if options.skip_bad_rows => {
+            // Dead letters keep the row as it was read.
+            processor.skip_row(headers, record, bad_row_reason(headers, resolved, &e));
+            Ok(None)
+        }
+        Err(e) => Err(e).context(ParseError("record from CSV")),
+    }
+}
+
+/// A copy of `record` with the transaction type replaced, if it is one of
+/// `options.type_aliases`.
+fn resolve_type_alias(
+    headers: &StringRecord,
+    record: &StringRecord,
+    options: &InputOptions,
+) -> Option<StringRecord> {
+    if options.type_aliases.is_empty() {
+        return None;
+    }
+    let index = headers.iter().position(|x| x == "type")?;
+    let transaction_type = options.type_aliases.get(record.get(index)?)?.to_string();
+    let mut resolved: StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match i == index {
+            true => transaction_type.as_str(),
+            false => field,
+        })
+        .collect();
+    resolved.set_position(record.position().cloned());
+    Some(resolved)
+}
+
+/// Tell a transaction type that isn't supported (yet) apart from corrupt
+/// data, since they are usually handled differently.
+fn bad_row_reason(headers: &StringRecord, record: &StringRecord, error: &csv::Error) -> String {
+    let transaction_type = headers
+        .iter()
+        .position(|x| x == "type")
+        .and_then(|i| record.get(i));
+    match transaction_type {
+        Some(name) if name.parse::<TransactionType>().is_err() => {
+            format!("unknown transaction type: {name}")
+        }
+        _ => format!("malformed row: {error}"),
+    }
+}
+
+/// Where and how often `process_csv_checkpointed` saves checkpoints.
+#[non_exhaustive]
+#[derive(Debug, Clone, bon::Builder)]
+pub struct CheckpointOptions {
+    #[builder(into)]
+    pub path: PathBuf,
+    /// Save a checkpoint after every this many records.
+    pub interval: u64,
+}
+
+/// Same as `process_csv_with`, but periodically saves a `Checkpoint`.
+///
+/// The first `skip` records are skipped, to resume from a checkpoint that
+/// has already been restored into the processor's database. Only use this
+/// when nothing else is sending to the processor, since each checkpoint
+/// waits for all sent transactions to be applied.
+pub fn process_csv_checkpointed<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    input: R,
+    options: &InputOptions,
+    checkpoint: &CheckpointOptions,
+    skip: u64,
+) -> anyhow::Result<()> {
+    let mut reader = csv_reader(input, options)?;
+    if is_empty(&mut reader)? {
+        return Ok(());
+    }
+    let headers = reader.headers()?.clone();
+    let interval = checkpoint.interval.max(1);
+    // The limit counts from the start of the input, including skipped records.
+    let limit = options.limit.unwrap_or(u64::MAX);
+    let mut record = StringRecord::new();
+    let mut records = 0;
+    while records < limit && read_record(&mut reader, &mut record)? {
+        records += 1;
+        if records <= skip {
+            continue;
+        }
+        if let Some(transaction) = parse_record(processor, &headers, &record, options)? {
+            processor.send_transaction(transaction)?;
+        }
+
+        if records.is_multiple_of(interval) {
+            // Only snapshot once everything up to this record is applied.
+            processor.sync()?;
+            Checkpoint::new(records, processor.database()).save(&checkpoint.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Same as `process_csv`, but reuses a single `StringRecord` and maps the
+/// fields manually instead of going through serde for every row.
+pub fn process_csv_records<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    input: R,
+) -> anyhow::Result<()> {
+    let mut reader = csv_reader(input, &InputOptions::default())?;
+    if is_empty(&mut reader)? {
+        return Ok(());
+    }
+    let columns = Columns::from_headers(reader.headers()?).context(ParseError("CSV header"))?;
+    let mut record = StringRecord::new();
+    while read_record(&mut reader, &mut record)? {
+        let transaction = columns
+            .parse(&record)
+            .context(ParseError("record from CSV"))?;
+        processor.send_transaction(transaction)?;
+    }
+    Ok(())
+}
+
+/// A batch of transactions in a structured document.
+#[derive(Deserialize)]
+struct Batch {
+    transactions: Vec<Transaction>,
+}
+
+/// Process a TOML document containing a `[[transactions]]` array.
+///
+/// Each entry uses the same field names as the CSV columns. Amounts are
+/// strings (e.g. `amount = "1.5"`) so they parse exactly as they do in CSV.
+pub fn process_toml<R: std::io::Read, S: AccountStore>(
+    processor: &Processor<S>,
+    mut input: R,
+) -> anyhow::Result<()> {
+    let mut document = String::new();
+    input.read_to_string(&mut document)?;
+    if document.trim().is_empty() {
+        return Ok(());
+    }
+    let batch: Batch = toml::from_str(&document).context(ParseError("TOML batch"))?;
+    for transaction in batch.transactions {
+        processor.send_transaction(transaction)?;
+    }
+    Ok(())
+}
+
+/// A row of a seed file.
+#[derive(Deserialize)]
+struct SeedRecord {
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    available: NonNegativeCurrency,
+}
+
+/// Create accounts with opening balances from a `client,available` CSV,
+/// returning the number of accounts created. See
+/// `Account::with_opening_balance`.
+///
+/// This must be done before processing any transactions. Each client may
+/// only be seeded once, and not if its account already exists.
+pub fn load_seed<R: std::io::Read, S: AccountStore>(
+    database: &AccountDatabase<S>,
+    input: R,
+) -> anyhow::Result<usize> {
+    let mut reader = csv_reader(input, &InputOptions::default())?;
+    let mut seeded = HashSet::new();
+    for record in reader.deserialize() {
+        let record: SeedRecord = record.context(ParseError("seed record"))?;
+        if !seeded.insert(record.client_id) || database.get_account(record.client_id).is_some() {
+            return Err(anyhow::anyhow!(
+                "client {} is already seeded or has an account",
+                record.client_id
+            ));
+        }
+        database.restore(vec![Account::with_opening_balance(
+            record.client_id,
+            record.available.get(),
+        )]);
+    }
+    Ok(seeded.len())
+}
+
+/// A row of an alias file.
+#[derive(Deserialize)]
+struct AliasRecord {
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    name: String,
+}
+
+/// Read display names for the output's `name` column from a `client,name`
+/// CSV. Names only affect the output, not the accounts.
+pub fn load_aliases<R: std::io::Read>(input: R) -> anyhow::Result<HashMap<ClientId, String>> {
+    let mut reader = csv_reader(input, &InputOptions::default())?;
+    let mut aliases = HashMap::new();
+    for record in reader.deserialize() {
+        let record: AliasRecord = record.context(ParseError("alias record"))?;
+        if aliases.insert(record.client_id, record.name).is_some() {
+            return Err(anyhow::anyhow!(
+                "client {} has more than one name",
+                record.client_id
+            ));
+        }
+    }
+    Ok(aliases)
+}
+
+/// Positions of each known column in the CSV header.
+struct Columns {
+    transaction_type: usize,
+    client_id: usize,
+    transaction_id: usize,
+    amount: Option<usize>,
+    reference: Option<usize>,
+    timestamp: Option<usize>,
+    sequence: Option<usize>,
+    settled: Option<usize>,
+}
+
+impl Columns {
+    fn from_headers(headers: &StringRecord) -> anyhow::Result<Self> {
+        let find = |name: &str| headers.iter().position(|x| x == name);
+        let require = |name: &str| find(name).with_context(|| format!("missing column: {name}"));
+        Ok(Self {
+            transaction_type: require("type")?,
+            client_id: require("client")?,
+            transaction_id: require("tx")?,
+            amount: find("amount"),
+            reference: find("reference"),
+            timestamp: find("timestamp"),
+            sequence: find("sequence"),
+            settled: find("settled"),
+        })
+    }
+
+    fn parse(&self, record: &StringRecord) -> anyhow::Result<Transaction> {
+        let field = |index: usize| record.get(index).unwrap_or_default();
+        // Empty fields are treated the same as missing ones.
+        let optional = |index: Option<usize>| index.map(field).filter(|x| !x.is_empty());
+
+        Transaction::try_from(TransactionRecord {
+            transaction_type: field(self.transaction_type).parse()?,
+            client_id: field(self.client_id).parse()?,
+            transaction_id: field(self.transaction_id).parse()?,
+            amount: optional(self.amount).map(str::parse).transpose()?,
+            reference: optional(self.reference).map(str::to_string),
+            timestamp: optional(self.timestamp).map(str::parse).transpose()?,
+            sequence: optional(self.sequence).map(str::parse).transpose()?,
+            settled: optional(self.settled).map(str::parse).transpose()?,
+            fee: None,
+            froze_account: None,
+        })
+    }
+}
+
+/// Process a file. Files with a `.zst` extension are decompressed first
+/// (requires the `zstd` feature). Other files are memory-mapped if
+/// `options.mmap` is set.
+pub fn process_file<S: AccountStore>(
+    processor: &Processor<S>,
+    path: &Path,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    let f = File::open(path).with_context(|| format!("failed to open file: {}", path.display()))?;
+    let result = match (path.extension().is_some_and(|x| x == "zst"), options.mmap) {
+        (true, _) => process_zstd(processor, f, options),
+        (false, true) => process_mmap(processor, &f, options),
+        (false, false) => process_reader(processor, f, options),
+    };
+    result.with_context(|| format!("failed to process file: {}", path.display()))
+}
+
+/// Process a file by memory-mapping it and reading it as a byte slice,
+/// which avoids a read call for every buffer-full.
+///
+/// The file must not be modified while it is being processed, by this or
+/// any other process. Changes would show up part way through, and
+/// truncating the file can crash the process.
+#[cfg(feature = "mmap")]
+fn process_mmap<S: AccountStore>(
+    processor: &Processor<S>,
+    f: &File,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    // SAFETY: the file is only read, and callers must not modify it while
+    // it is mapped (see above).
+    let map = unsafe { memmap2::Mmap::map(f) }.context("failed to memory-map file")?;
+    process_reader(processor, &map[..], options)
+}
+
+#[cfg(not(feature = "mmap"))]
+fn process_mmap<S: AccountStore>(
+    _processor: &Processor<S>,
+    _f: &File,
+    _options: &InputOptions,
+) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "memory-mapped input is not supported (build with the `mmap` feature)"
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn process_zstd<S: AccountStore>(
+    processor: &Processor<S>,
+    f: File,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    let decoder = zstd::Decoder::new(f).context("failed to initialise zstd decoder")?;
+    process_reader(processor, decoder, options)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn process_zstd<S: AccountStore>(
+    _processor: &Processor<S>,
+    _f: File,
+    _options: &InputOptions,
+) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "zstd input is not supported (build with the `zstd` feature)"
+    ))
+}
+
+/// Process several files, reading up to `parallelism` of them at once.
+///
+/// Transactions for a given client are applied in order within each file,
+/// but when `parallelism > 1` there is no ordering guarantee between files.
+/// With `parallelism == 1` the files are processed one after another.
+pub fn process_files<P: AsRef<Path> + Sync>(
+    processor: &Processor,
+    paths: &[P],
+    parallelism: usize,
+    options: &InputOptions,
+) -> anyhow::Result<()> {
+    let next = AtomicUsize::new(0);
+    let readers = parallelism.clamp(1, paths.len().max(1));
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..readers)
+            .map(|_| {
+                scope.spawn(|| loop {
+                    let Some(path) = paths.get(next.fetch_add(1, Ordering::SeqCst)) else {
+                        return Ok(());
+                    };
+                    process_file(processor, path.as_ref(), options)?;
+                })
+            })
+            .collect();
+
+        // Wait for every reader before reporting the first error.
+        let results: Vec<anyhow::Result<()>> = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("reader thread panicked"))
+            .collect();
+        results.into_iter().collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, io::Cursor};
+
+    use super::*;
+
+    use crate::{
+        account::{AccountDatabase, ClientId, OutputColumn, OutputOptions},
+        currency::Currency,
+        dead_letter::{DeadLetter, DeadLetterEntry},
+        processor::ProcessorConfig,
+        transaction::TransactionId,
+    };
+
+    fn lines_sorted(input: &str) -> HashSet<String> {
+        input.lines().map(|x| x.to_string()).collect()
+    }
+
+    const EXAMPLE_DATA: &str = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0"#;
+
+    fn process_str(input: &str) -> AccountDatabase {
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        process_csv(&processor, Cursor::new(input)).unwrap();
+        processor.close();
+        database
+    }
+
+    #[test]
+    fn test_type_aliases() {
+        let input =
+            "type, client, tx, amount\ncredit, 1, 1, 5.0\ndebit, 1, 2, 1.5\ndeposit, 2, 3, 1.0\n";
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let options = InputOptions::builder()
+            .type_aliases(HashMap::from([
+                ("credit".to_string(), TransactionType::Deposit),
+                ("debit".to_string(), TransactionType::Withdrawal),
+            ]))
+            .build();
+        process_csv_with(&processor, input.as_bytes(), &options).unwrap();
+        processor.close_and_verify().unwrap();
+
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.available, Currency::from_f64(3.5));
+        assert_eq!(
+            account.history[0].transaction_type,
+            TransactionType::Deposit
+        );
+        assert_eq!(
+            database
+                .get_account(ClientId::from(2))
+                .unwrap()
+                .lock()
+                .unwrap()
+                .available,
+            Currency::from_f64(1.0)
+        );
+
+        // Without the aliases, the row is rejected.
+        let processor = Processor::new(AccountDatabase::default());
+        process_csv(&processor, input.as_bytes()).expect_err("unknown type");
+        processor.close();
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let options = InputOptions::builder().delimiter(b';').build();
+        let input = EXAMPLE_DATA.replace(',', ";");
+        process_csv_with(&processor, Cursor::new(input), &options).unwrap();
+        processor.close();
+
+        let mut output = Vec::new();
+        database.output_data(&mut output).unwrap();
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output).unwrap()),
+            lines_sorted(
+                "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2.0,0,2.0,false"
+            )
+        );
+    }
+
+    #[test]
+    fn test_example_data() {
+        let database = process_str(EXAMPLE_DATA);
+
+        let mut output = Cursor::new(Vec::new());
+        database.output_data(&mut output).unwrap();
+        database.verify_all_accounts();
+
+        let expected_output = r#"client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2.0,0,2.0,false"#;
+
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(expected_output)
+        );
+    }
+
+    #[test]
+    fn test_bom_stripped() {
+        let input = format!("\u{feff}{EXAMPLE_DATA}");
+        for process in [process_csv::<&[u8], _>, process_csv_records::<&[u8], _>] {
+            let database = AccountDatabase::default();
+            let processor = Processor::new(database.clone());
+            process(&processor, input.as_bytes()).unwrap();
+            // Only the overdrawn withdrawal is rejected.
+            assert_eq!(processor.close().failed, 1);
+
+            let mut output = Cursor::new(Vec::new());
+            database.output_data(&mut output).unwrap();
+            assert_eq!(
+                lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+                lines_sorted(
+                    "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2.0,0,2.0,false"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_negative_amount_rejected_when_parsing() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, -1.0";
+        for process in [process_csv::<&[u8], _>, process_csv_records::<&[u8], _>] {
+            let processor = Processor::new(AccountDatabase::default());
+            let err = process(&processor, input.as_bytes()).expect_err("negative amount");
+            assert!(format!("{err:#}")
+                .contains("transaction amount must not be negative: -1.0 (client 1, tx 1)"));
+            assert_eq!(processor.close().received, 0);
+        }
+    }
+
+    #[test]
+    fn test_empty_input() {
+        for input in ["", "type, client, tx, amount\n"] {
+            for process in [process_csv::<&[u8], _>, process_csv_records::<&[u8], _>] {
+                let processor = Processor::new(AccountDatabase::default());
+                process(&processor, input.as_bytes()).unwrap();
+                assert_eq!(processor.close().received, 0);
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv.zst");
+        let compressed = zstd::encode_all(EXAMPLE_DATA.as_bytes(), 0).unwrap();
+        std::fs::write(&path, compressed).unwrap();
+
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        process_file(&processor, &path, &InputOptions::default()).unwrap();
+        processor.close();
+
+        let mut output = Cursor::new(Vec::new());
+        database.output_data(&mut output).unwrap();
+        let mut expected = Cursor::new(Vec::new());
+        process_str(EXAMPLE_DATA)
+            .output_data(&mut expected)
+            .unwrap();
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(&String::from_utf8(expected.into_inner()).unwrap())
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, EXAMPLE_DATA).unwrap();
+
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let options = InputOptions::builder().mmap(true).build();
+        process_file(&processor, &path, &options).unwrap();
+        processor.close();
+
+        let mut output = Cursor::new(Vec::new());
+        database.output_data(&mut output).unwrap();
+        let mut expected = Cursor::new(Vec::new());
+        process_str(EXAMPLE_DATA)
+            .output_data(&mut expected)
+            .unwrap();
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(&String::from_utf8(expected.into_inner()).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_example_data_conserved() {
+        process_str(EXAMPLE_DATA).conservation_check().unwrap();
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CheckpointOptions::builder()
+            .path(dir.path().join("checkpoint.json"))
+            .interval(2)
+            .build();
+
+        // A partial run that stops after the third record.
+        let partial: String = EXAMPLE_DATA.lines().take(4).collect::<Vec<_>>().join("\n");
+        let processor = Processor::new(AccountDatabase::default());
+        process_csv_checkpointed(
+            &processor,
+            partial.as_bytes(),
+            &InputOptions::default(),
+            &options,
+            0,
+        )
+        .unwrap();
+        processor.close();
+
+        let checkpoint = Checkpoint::load(&options.path).unwrap();
+        assert_eq!(checkpoint.records, 2);
+        let skip = checkpoint.records;
+        let database = AccountDatabase::default();
+        checkpoint.restore(&database);
+        let processor = Processor::new(database.clone());
+        process_csv_checkpointed(
+            &processor,
+            EXAMPLE_DATA.as_bytes(),
+            &InputOptions::default(),
+            &options,
+            skip,
+        )
+        .unwrap();
+        // Only the records after the checkpoint were sent.
+        assert_eq!(processor.close().received, 3);
+        assert_eq!(Checkpoint::load(&options.path).unwrap().records, 4);
+
+        let mut output = Cursor::new(Vec::new());
+        database.output_data(&mut output).unwrap();
+        let mut expected = Cursor::new(Vec::new());
+        process_str(EXAMPLE_DATA)
+            .output_data(&mut expected)
+            .unwrap();
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(&String::from_utf8(expected.into_inner()).unwrap())
+        );
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_client_ids() {
+        let input = r#"type, client, tx, amount
+deposit, 3, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 2, 3, 2.0
+deposit, 1, 4, 1.5"#;
+
+        let database = process_str(input);
+        assert_eq!(
+            database.client_ids(),
+            vec![ClientId::from(1), ClientId::from(2), ClientId::from(3)]
+        );
+
+        let database = process_str(EXAMPLE_DATA);
+        assert_eq!(
+            database.client_ids(),
+            vec![ClientId::from(1), ClientId::from(2)]
+        );
+
+        // Accounts in use are not waited for.
+        let account = database.account(ClientId::from(1));
+        let _guard = account.lock().unwrap();
+        assert_eq!(database.client_ids().len(), 2);
+    }
+
+    #[test]
+    fn test_reference_preserved() {
+        let input = r#"type, client, tx, amount, reference
+deposit, 1, 1, 1.0, INV-001
+deposit, 1, 2, 2.0,
+withdrawal, 1, 3, 0.5, case 42"#;
+
+        let database = process_str(input);
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        let references: Vec<Option<&str>> = account
+            .history
+            .iter()
+            .map(|x| x.reference.as_deref())
+            .collect();
+        assert_eq!(references, vec![Some("INV-001"), None, Some("case 42")]);
+        assert_eq!(
+            account.transactions[&TransactionId::from(1)]
+                .reference
+                .as_deref(),
+            Some("INV-001")
+        );
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_trim_none_preserves_reference() {
+        let input = "type,client,tx,amount,reference\ndeposit,1,1,1.0,  INV-001 ";
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let options = InputOptions::builder().trim(TrimMode::None).build();
+        process_csv_with(&processor, input.as_bytes(), &options).unwrap();
+        processor.close();
+
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.history[0].reference.as_deref(), Some("  INV-001 "));
+
+        // The default trims the reference along with everything else.
+        let database = process_str(input);
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.history[0].reference.as_deref(), Some("INV-001"));
+    }
+
+    #[test]
+    fn test_report() {
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        process_csv(&processor, Cursor::new(EXAMPLE_DATA)).unwrap();
+        let metrics = processor.close();
+
+        let report = format!("{metrics}{}", database.totals().unwrap());
+        for expected in [
+            "Transactions received: 5",
+            "Transactions applied:  4",
+            "Transactions failed:   1",
+            "Accounts:  2 (0 locked)",
+            "Available: 3.5",
+            "Held:      0",
+            "Total:     3.5",
+        ] {
+            assert!(
+                report.contains(expected),
+                "missing {expected:?} in:\n{report}"
+            );
+        }
+
+        let account_mutex = database.account(ClientId::from(1));
+        let summary = account_mutex.lock().unwrap().summary().to_string();
+        assert_eq!(
+            summary,
+            "Client 1\n  Available: 1.5\n  Held:      0\n  Total:     1.5\n  Locked:    false\n"
+        );
+    }
+
+    #[test]
+    fn test_trailing_comma() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 5.0,\ndeposit, 1, 2, 1.0\n";
+        let database = process_str(input);
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(account_mutex.lock().unwrap().total, Currency::from_f64(6.0));
+
+        let processor = Processor::new(AccountDatabase::default());
+        process_csv_records(&processor, input.as_bytes()).unwrap();
+        assert_eq!(processor.close().applied, 2);
+
+        // Only a single, empty extra field is tolerated.
+        for input in [
+            "type, client, tx, amount\ndeposit, 1, 1, 5.0, x\n",
+            "type, client, tx, amount\ndeposit, 1, 1, 5.0,,\n",
+        ] {
+            let processor = Processor::new(AccountDatabase::default());
+            let err = process_csv(&processor, input.as_bytes()).expect_err("extra field");
+            assert!(format!("{err:#}").contains("but the header has 4"));
+            processor.close();
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 1, 3, 4.0
+";
+        for (limit, applied, total) in [(0, 0, 0.0), (2, 2, 3.0), (10, 3, 7.0)] {
+            let database = AccountDatabase::default();
+            let processor = Processor::new(database.clone());
+            let options = InputOptions::builder().limit(limit).build();
+            process_csv_with(&processor, Cursor::new(input), &options).unwrap();
+            assert_eq!(processor.close().applied, applied);
+            assert_eq!(database.totals().unwrap().total, Currency::from_f64(total));
+        }
+    }
+
+    #[test]
+    fn test_skip_bad_rows() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+refund, 1, 2, 1.0
+deposit, 1, 3, abc
+deposit, 1, 4, 2.0
+";
+        let database = AccountDatabase::default();
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder().dead_letters(dead_tx).build();
+        let processor = Processor::with_config(database.clone(), config);
+        let options = InputOptions::builder().skip_bad_rows(true).build();
+        process_csv_with(&processor, Cursor::new(input), &options).unwrap();
+        let metrics = processor.close();
+        assert_eq!(metrics.applied, 2);
+        assert_eq!(metrics.skipped_rows, 2);
+
+        let dead_letters: Vec<DeadLetter> = dead_rx.try_iter().collect();
+        let reasons: Vec<&str> = dead_letters.iter().map(|x| x.reason.as_str()).collect();
+        assert_eq!(reasons[0], "unknown transaction type: refund");
+        assert!(reasons[1].starts_with("malformed row:"), "{}", reasons[1]);
+        let DeadLetterEntry::Row { row, .. } = &dead_letters[0].entry else {
+            panic!("expected a raw row");
+        };
+        assert_eq!(row, &StringRecord::from(vec!["refund", "1", "2", "1.0"]));
+
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(account_mutex.lock().unwrap().total, Currency::from_f64(7.0));
+
+        // Without the option the first bad row is an error.
+        let processor = Processor::new(AccountDatabase::default());
+        process_csv(&processor, Cursor::new(input)).expect_err("bad row");
+        processor.close();
+    }
+
+    #[test]
+    fn test_adjustment_parsed() {
+        let input = "type, client, tx, amount, reference
+deposit, 1, 1, 10.0,
+adjustment, 1, 2, -2.5, duplicate credit
+adjustment, 1, 3, 1.0, goodwill";
+        let database = process_str(input);
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(8.5)
+        );
+        database.conservation_check().unwrap();
+
+        let input = "type, client, tx, amount\nadjustment, 1, 1, 1.0";
+        let processor = Processor::new(AccountDatabase::default());
+        let err = process_csv(&processor, input.as_bytes()).expect_err("no reference");
+        assert!(format!("{err:#}").contains("reference required for adjustment"));
+    }
+
+    #[test]
+    fn test_load_aliases() {
+        let aliases = load_aliases("client,name\n1,Alice\n2,\"Smith, Bob\"\n".as_bytes()).unwrap();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases[&ClientId::from(1)], "Alice");
+        assert_eq!(aliases[&ClientId::from(2)], "Smith, Bob");
+
+        let err =
+            load_aliases("client,name\n1,Alice\n1,Bob\n".as_bytes()).expect_err("duplicate client");
+        assert_eq!(err.to_string(), "client 1 has more than one name");
+    }
+
+    #[test]
+    fn test_load_seed() {
+        let database = AccountDatabase::default();
+        let seed = "client, available\n1, 50.0\n2, 5.0\n";
+        assert_eq!(load_seed(&database, seed.as_bytes()).unwrap(), 2);
+        let processor = Processor::new(database.clone());
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,";
+        process_csv(&processor, input.as_bytes()).unwrap();
+        processor.close_and_verify().unwrap();
+
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.available, Currency::from_f64(50.0));
+        assert_eq!(account.held, Currency::from_f64(10.0));
+        assert_eq!(account.total, Currency::from_f64(60.0));
+        drop(account);
+        database.conservation_check().unwrap();
+
+        let err = load_seed(&database, "client,available\n2,1.0\n".as_bytes())
+            .expect_err("already seeded");
+        assert!(err.to_string().contains("client 2 is already seeded"));
+        let err = load_seed(
+            &AccountDatabase::default(),
+            "client,available\n3,-1\n".as_bytes(),
+        )
+        .expect_err("negative");
+        assert!(format!("{err:#}").contains("amount must not be negative"));
+    }
+
+    #[test]
+    fn test_empty_amount() {
+        let input = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, "#;
+
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let err = process_csv(&processor, Cursor::new(input))
+            .expect_err("empty deposit amount should be rejected");
+        processor.close();
+        assert!(format!("{err:#}").contains("amount required"));
+
+        let input = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1, "#;
+
+        let database = process_str(input);
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.held, Currency::from_f64(1.0));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_process_files_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for file in 0..20u32 {
+            let mut contents = String::from("type,client,tx,amount\n");
+            for client in 1..=5u32 {
+                let tx = file * 100 + client * 2;
+                contents.push_str(&format!("deposit,{client},{tx},{client}.5\n"));
+                contents.push_str(&format!("withdrawal,{client},{},1.0\n", tx + 1));
+            }
+            let path = dir.path().join(format!("input-{file}.csv"));
+            std::fs::write(&path, contents).unwrap();
+            paths.push(path);
+        }
+
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder().workers(4).build();
+        let processor = Processor::with_config(database.clone(), config);
+        process_files(&processor, &paths, 4, &InputOptions::default()).unwrap();
+        let metrics = processor.close();
+
+        assert_eq!(metrics.applied, 200);
+        assert_eq!(metrics.failed, 0);
+        for client in 1..=5u16 {
+            let account_mutex = database.account(ClientId::from(client));
+            let account = account_mutex.lock().unwrap();
+            let expected = (f64::from(client) + 0.5 - 1.0) * 20.0;
+            assert_eq!(account.available, Currency::from_f64(expected));
+            assert_eq!(account.history.len(), 40);
+        }
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_output_precision() {
+        let input = r#"type, client, tx, amount
+deposit, 1, 1, 0.000000015"#;
+
+        for (precision, expected) in [
+            (8, "1,0.00000002,0,0.00000002,false"),
+            (4, "1,0.0000,0,0.0000,false"),
+        ] {
+            let database = AccountDatabase::with_precision(precision);
+            let processor = Processor::new(database.clone());
+            process_csv(&processor, Cursor::new(input)).unwrap();
+            processor.close();
+
+            let mut output = Cursor::new(Vec::new());
+            database.output_data(&mut output).unwrap();
+            let output = String::from_utf8(output.into_inner()).unwrap();
+            assert_eq!(output.lines().nth(1), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_fixed_decimals() {
+        let database = process_str(EXAMPLE_DATA);
+        let options = OutputOptions::builder().fixed_decimals(true).build();
+        let mut output = Cursor::new(Vec::new());
+        database.output_data_with(&mut output, &options).unwrap();
+
+        let expected_output = r#"client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false"#;
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(expected_output)
+        );
+    }
+
+    #[test]
+    fn test_wide_output() {
+        let input = r#"type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 5.0
+withdrawal, 1, 3, 2.5
+dispute, 1, 2,"#;
+        let database = process_str(input);
+        let options = OutputOptions::builder()
+            .columns(OutputColumn::WIDE.to_vec())
+            .build();
+        let mut output = Cursor::new(Vec::new());
+        database.output_data_with(&mut output, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "client,available,held,total,locked,deposited,withdrawn,disputes\n\
+             1,7.5,5.0,12.5,false,15.0,2.5,1\n"
+        );
+    }
+
+    #[test]
+    fn test_timestamp_parsed() {
+        let input = r#"type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 2024-06-01T12:00:00Z
+deposit, 1, 2, 1.0,"#;
+
+        let database = process_str(input);
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        let timestamps: Vec<Option<String>> = account
+            .history
+            .iter()
+            .map(|x| x.timestamp.map(|t| t.to_rfc3339()))
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![Some("2024-06-01T12:00:00+00:00".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn test_settled_parsed() {
+        let input = "type, client, tx, amount, settled
+deposit, 1, 1, 1.0, false
+deposit, 1, 2, 1.0,
+dispute, 1, 1,,
+dispute, 1, 2,,
+";
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        process_csv(&processor, Cursor::new(input)).unwrap();
+        let metrics = processor.close();
+        // Only the dispute of the unsettled deposit fails.
+        assert_eq!(metrics.failed, 1);
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(account_mutex.lock().unwrap().held, Currency::from_f64(1.0));
+    }
+
+    #[test]
+    fn test_process_csv_records_matches_deserialize() {
+        let mixed = r#"type, client, tx, amount, reference, timestamp
+deposit, 1, 1, 10.0, INV-1, 2024-06-01T12:00:00Z
+deposit, 2, 2, 5.5, ,
+dispute, 1, 1, , ,
+withdrawal, 2, 3, 1.25, ,
+resolve, 1, 1, , ,
+chargeback, 2, 2, , ,"#;
+
+        for input in [EXAMPLE_DATA, mixed] {
+            let expected = process_str(input);
+
+            let database = AccountDatabase::default();
+            let processor = Processor::new(database.clone());
+            process_csv_records(&processor, Cursor::new(input)).unwrap();
+            processor.close();
+
+            let output = |database: &AccountDatabase| {
+                let mut output = Cursor::new(Vec::new());
+                database.output_data(&mut output).unwrap();
+                lines_sorted(&String::from_utf8(output.into_inner()).unwrap())
+            };
+            assert_eq!(output(&database), output(&expected));
+            for client_id in expected.client_ids() {
+                assert_eq!(
+                    *database.account(client_id).lock().unwrap(),
+                    *expected.account(client_id).lock().unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_toml_batch() {
+        let input = r#"
+[[transactions]]
+type = "deposit"
+client = 1
+tx = 1
+amount = "10.5"
+
+[[transactions]]
+type = "withdrawal"
+client = 1
+tx = 2
+amount = "0.25"
+reference = "ATM"
+
+[[transactions]]
+type = "deposit"
+client = 2
+tx = 3
+amount = "3.0"
+
+[[transactions]]
+type = "dispute"
+client = 2
+tx = 3
+"#;
+
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        let options = InputOptions::builder().format(InputFormat::Toml).build();
+        process_reader(&processor, Cursor::new(input), &options).unwrap();
+        processor.close();
+
+        let mut output = Cursor::new(Vec::new());
+        database.output_data(&mut output).unwrap();
+        let expected_output = r#"client,available,held,total,locked
+1,10.25,0,10.25,false
+2,0.0,3.0,3.0,false"#;
+        assert_eq!(
+            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
+            lines_sorted(expected_output)
+        );
+        database.verify_all_accounts();
+
+        // Amounts must be strings, as in CSV.
+        let input = "[[transactions]]\ntype = \"deposit\"\nclient = 1\ntx = 1\namount = 1.5\n";
+        let processor = Processor::new(AccountDatabase::default());
+        process_toml(&processor, Cursor::new(input)).expect_err("float amounts should be rejected");
+        processor.close();
+    }
+
+    #[test]
+    fn test_process_lines() {
+        use std::{io::Write, sync::mpsc::channel, time::Duration};
+
+        let (log_tx, log_rx) = channel();
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder().event_log(log_tx).build();
+        let processor = Processor::with_config(database.clone(), config);
+        let (reader, mut writer) = std::io::pipe().unwrap();
+
+        thread::scope(|s| {
+            let handle = s.spawn(|| {
+                process_lines(&processor, BufReader::new(reader), &InputOptions::default())
+            });
+
+            // Each line is applied as soon as it arrives, before any more input.
+            writer
+                .write_all("\u{feff}type, client, tx, amount\n".as_bytes())
+                .unwrap();
+            writer.write_all(b"deposit, 1, 1, 5.0\n").unwrap();
+            let logged = log_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(logged.transaction_id, TransactionId::from(1));
+
+            writer.write_all(b"\n").unwrap();
+            thread::sleep(Duration::from_millis(20));
+            writer.write_all(b"withdrawal, 1, 2, 1.5,\r\n").unwrap();
+            let logged = log_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(logged.transaction_id, TransactionId::from(2));
+
+            drop(writer);
+            assert_eq!(handle.join().unwrap().unwrap(), 2);
+        });
+        processor.close_and_verify().unwrap();
+
+        let account = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(account.lock().unwrap().available, Currency::from_f64(3.5));
+
+        let processor = Processor::new(AccountDatabase::default());
+        let input = "type,client,tx,amount\ndeposit,1,1\n";
+        let err = process_lines(&processor, Cursor::new(input), &InputOptions::default())
+            .expect_err("short record should be rejected");
+        assert!(format!("{err:#}").contains("record on line 2 has 3 fields"));
+        processor.close();
+    }
+}
@@ -0,0 +1,42 @@
+use std::io::Read;
+
+use account::AccountDatabase;
+use processor::Processor;
+
+pub mod account;
+pub mod checkpoint;
+pub mod clock;
+pub mod currency;
+pub mod dead_letter;
+pub mod error;
+pub mod event_log;
+pub mod input;
+pub mod processor;
+pub mod store;
+pub mod transaction;
+
+/// Process CSV transactions from `input` with the default settings and
+/// return the resulting accounts.
+///
+/// Transactions that fail are logged and skipped, as with `process_csv`.
+///
+/// ```
+/// use std::io::Cursor;
+///
+/// let csv = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0\n";
+/// let database = money_project::run(Cursor::new(csv)).unwrap();
+///
+/// let mut output = Vec::new();
+/// database.output_data(&mut output).unwrap();
+/// assert_eq!(
+///     String::from_utf8(output).unwrap(),
+///     "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+/// );
+/// ```
+pub fn run<R: Read>(input: R) -> anyhow::Result<AccountDatabase> {
+    let database = AccountDatabase::default();
+    let processor = Processor::new(database.clone());
+    input::process_csv(&processor, input)?;
+    processor.close();
+    Ok(database)
+}
@@ -1,82 +1,794 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::mpsc::channel,
+    thread,
+};
 
-use account::AccountDatabase;
 use anyhow::Context;
-use csv::ReaderBuilder;
-use processor::Processor;
-use transaction::Transaction;
+use clap::Parser;
+use money_project::{
+    account::{AccountDatabase, ClientId, LockedFormat, OutputColumn, OutputOptions},
+    checkpoint::Checkpoint,
+    currency::{Currency, NonNegativeCurrency, DECIMAL_PLACES},
+    dead_letter::write_dead_letters,
+    error::{FailureLimitReached, ParseError},
+    event_log::{write_event_log, EventLogFormat},
+    input::{
+        load_aliases, load_seed, process_csv_checkpointed, process_file, process_files,
+        process_lines, CheckpointOptions, InputFormat, InputOptions, TrimMode,
+    },
+    processor::{ApplyOptions, Processor, ProcessorConfig},
+    transaction::TransactionType,
+};
+use tempfile::NamedTempFile;
 
-mod account;
-mod currency;
-mod processor;
-mod transaction;
+/// Transaction simulator.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// The CSV file(s) containing transactions.
+    #[arg(required_unless_present = "stream")]
+    filenames: Vec<PathBuf>,
+    /// Read CSV from stdin instead, applying each line as soon as it
+    /// arrives (quoted fields can't span lines).
+    #[arg(long, conflicts_with_all = ["filenames", "checkpoint", "resume"])]
+    stream: bool,
+    /// Format of the input files (csv or toml).
+    #[arg(long, default_value_t = InputFormat::Csv)]
+    format: InputFormat,
+    /// Whitespace trimming for CSV input (none, headers, fields or all).
+    #[arg(long, default_value_t = TrimMode::All)]
+    trim: TrimMode,
+    /// Field delimiter for CSV input. Use `\t` for tabs.
+    #[arg(long, default_value = ",", value_parser = parse_delimiter)]
+    delimiter: u8,
+    /// Extra names for transaction types, as comma-separated NAME=TYPE
+    /// pairs (e.g. credit=deposit,debit=withdrawal).
+    #[arg(long, value_name = "ALIASES", value_delimiter = ',', value_parser = parse_type_alias)]
+    type_alias: Vec<(String, TransactionType)>,
+    /// Number of files to read concurrently. With more than one, ordering
+    /// between files is not guaranteed (but is preserved within each file).
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+    /// Number of processing threads.
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+    /// Limit the number of transactions queued across all workers.
+    #[arg(long, value_name = "N")]
+    max_in_flight: Option<usize>,
+    /// Buffer up to N transactions and apply them in timestamp order.
+    #[arg(long, value_name = "N")]
+    reorder_window: Option<usize>,
+    /// Number of decimal places for amounts in the output.
+    #[arg(long, default_value_t = DECIMAL_PLACES)]
+    precision: u32,
+    /// Always show all decimal places in the output (e.g. 1.5000).
+    #[arg(long)]
+    fixed_decimals: bool,
+    /// Add deposited, withdrawn and dispute count columns to the output.
+    #[arg(long, conflicts_with = "columns")]
+    wide: bool,
+    /// Comma-separated output columns, in order (client, name, available,
+    /// held, total, locked, deposited, withdrawn, disputes, held_ratio).
+    #[arg(long, value_delimiter = ',')]
+    columns: Option<Vec<OutputColumn>>,
+    /// How to write the locked column (true-false, one-zero or
+    /// active-locked).
+    #[arg(long, default_value_t = LockedFormat::TrueFalse)]
+    locked_format: LockedFormat,
+    /// Leave out accounts that no transaction was ever applied to (created
+    /// by transactions that failed).
+    #[arg(long)]
+    omit_inactive: bool,
+    /// Read display names from a `client,name` CSV. Adds a name column
+    /// after the client unless `--columns` is given.
+    #[arg(long, value_name = "FILE")]
+    aliases: Option<PathBuf>,
+    /// Treat a repeated dispute for an already disputed transaction as a no-op.
+    #[arg(long)]
+    idempotent_disputes: bool,
+    /// Withdraw the full balance if a withdrawal would leave less than this.
+    #[arg(long, value_name = "THRESHOLD")]
+    sweep_dust: Option<Currency>,
+    /// Charge this fee on every withdrawal, credited to `--fee-account`.
+    #[arg(long, value_name = "AMOUNT", requires = "fee_account")]
+    withdrawal_fee: Option<NonNegativeCurrency>,
+    /// Comma-separated client ids whose transactions are always rejected.
+    #[arg(long, value_name = "CLIENTS", value_delimiter = ',')]
+    reserved_clients: Vec<ClientId>,
+    /// Reject withdrawals that would take a client's total for the day
+    /// (UTC) above this amount.
+    #[arg(long, value_name = "AMOUNT")]
+    daily_withdrawal_limit: Option<Currency>,
+    /// Freeze the account on chargebacks that only charge back part of the
+    /// disputed amount, as well as on full ones.
+    #[arg(long)]
+    freeze_on_partial_chargeback: bool,
+    /// Client account that collects withdrawal fees.
+    #[arg(long, value_name = "CLIENT", requires = "withdrawal_fee")]
+    fee_account: Option<ClientId>,
+    /// Don't keep transaction histories, only balances. Faster, but the
+    /// results can't be audited.
+    #[arg(long, conflicts_with = "check_conservation")]
+    balances_only: bool,
+    /// Reject amounts with more than N decimal places.
+    #[arg(long, value_name = "N")]
+    max_precision: Option<u32>,
+    /// Per-type limits on decimal places, as comma-separated TYPE=N pairs
+    /// (e.g. withdrawal=2). Overrides `--max-precision` for those types.
+    #[arg(long, value_name = "LIMITS", value_delimiter = ',', value_parser = parse_type_precision)]
+    type_precision: Vec<(TransactionType, u32)>,
+    /// Keep at most this many recent transactions in each account's history,
+    /// compacting older ones into a summary.
+    #[arg(long, value_name = "N")]
+    max_history: Option<usize>,
+    /// Verify that the account totals match the transaction histories.
+    #[arg(long)]
+    check_conservation: bool,
+    /// Periodically save a checkpoint to FILE (single CSV input only).
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+    /// Number of records between checkpoints.
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    checkpoint_interval: u64,
+    /// Create accounts with opening balances from a `client,available` CSV
+    /// before processing.
+    #[arg(long, value_name = "FILE")]
+    seed: Option<PathBuf>,
+    /// Resume from a checkpoint, skipping the records it already includes.
+    #[arg(long, value_name = "CHECKPOINT")]
+    resume: Option<PathBuf>,
+    /// Write rejected transactions to this CSV file.
+    #[arg(long, value_name = "FILE")]
+    dead_letters: Option<PathBuf>,
+    /// Write an append-only log of applied transactions to this file.
+    #[arg(long, value_name = "FILE")]
+    event_log: Option<PathBuf>,
+    /// Format of the event log (csv or ndjson).
+    #[arg(long, default_value_t, requires = "event_log")]
+    event_log_format: EventLogFormat,
+    /// Re-apply previously rejected transactions (as written by
+    /// `--dead-letters`) after processing the input files.
+    #[arg(long, value_name = "FILE")]
+    replay_failures: Option<PathBuf>,
+    /// Stop after reading N records from each CSV input.
+    #[arg(long, value_name = "N")]
+    limit: Option<u64>,
+    /// Stop processing once N transactions have failed. The output for the
+    /// transactions processed so far is still written.
+    #[arg(long, value_name = "N")]
+    max_failures: Option<u64>,
+    /// Skip CSV rows that can't be parsed (sending them to `--dead-letters`)
+    /// instead of stopping with an error.
+    #[arg(long)]
+    skip_bad_rows: bool,
+    /// Write the output to FILE instead of stdout. The file is only
+    /// replaced once the output is complete.
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Memory-map the input files instead of reading them (requires the
+    /// `mmap` feature). The files must not change while being processed.
+    #[arg(long, conflicts_with_all = ["stream", "checkpoint", "resume"])]
+    mmap: bool,
+    /// Print the output as an aligned table for reading in a terminal,
+    /// instead of CSV.
+    #[arg(long, conflicts_with = "output")]
+    pretty: bool,
+    /// Print a summary report to stderr after processing.
+    #[arg(long)]
+    report: bool,
+    /// Exit with a failure code if any transaction was rejected (or any row
+    /// skipped, with `--skip-bad-rows`).
+    /// The output is still written.
+    #[arg(long)]
+    strict: bool,
+}
+
+/// Parse a single-byte CSV delimiter. Tabs are hard to pass on a command
+/// line, so `\t` is also accepted.
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("delimiter must be a single ASCII character: {s:?}")),
+    }
+}
+
+/// Parse a `NAME=TYPE` transaction type alias.
+fn parse_type_alias(s: &str) -> Result<(String, TransactionType), String> {
+    let (name, transaction_type) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=TYPE: {s:?}"))?;
+    let transaction_type = transaction_type
+        .parse()
+        .map_err(|_| format!("unknown transaction type: {transaction_type:?}"))?;
+    Ok((name.to_string(), transaction_type))
+}
+
+/// Parse a `TYPE=N` per-type precision limit.
+fn parse_type_precision(s: &str) -> Result<(TransactionType, u32), String> {
+    let (transaction_type, places) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TYPE=N: {s:?}"))?;
+    let transaction_type = transaction_type
+        .parse()
+        .map_err(|_| format!("unknown transaction type: {transaction_type:?}"))?;
+    let places = places
+        .parse()
+        .map_err(|_| format!("invalid number of decimal places: {places:?}"))?;
+    Ok((transaction_type, places))
+}
+
+/// Exit code for errors reading or writing files.
+const EXIT_IO_ERROR: u8 = 2;
+/// Exit code for malformed input.
+const EXIT_PARSE_ERROR: u8 = 3;
+/// Exit code for any other failure while processing.
+const EXIT_PROCESSING_ERROR: u8 = 4;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     // NOTE: enable for logging.
     // tracing_subscriber::fmt::init();
 
-    let filename = std::env::args()
-        .nth(1)
-        .context("Please provide the CSV filename")?;
-    let path = Path::new(&filename);
-    let f = File::open(path).with_context(|| format!("failed to open file: {}", path.display()))?;
+    match run(&Args::parse(), std::io::stdout()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
 
-    let database = AccountDatabase::default();
-    let processor = Processor::new(database.clone());
-    let result = process_csv(&processor, f);
-    processor.close();
-    result?;
+/// Classify an error by the first recognised cause in its chain.
+fn exit_code(error: &anyhow::Error) -> u8 {
+    for cause in error.chain() {
+        if cause.is::<std::io::Error>() {
+            return EXIT_IO_ERROR;
+        }
+        if let Some(e) = cause.downcast_ref::<csv::Error>() {
+            return match e.is_io_error() {
+                true => EXIT_IO_ERROR,
+                false => EXIT_PARSE_ERROR,
+            };
+        }
+        if cause.is::<ParseError>() {
+            return EXIT_PARSE_ERROR;
+        }
+    }
+    EXIT_PROCESSING_ERROR
+}
 
-    database.output_data(std::io::stdout())?;
+/// Write a file via a temporary file in the same directory, renamed into
+/// place once `write` succeeds, so that a failure never leaves a partial
+/// file behind.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // The temporary file gets a unique name, and is removed on drop if it is
+    // never persisted.
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut temp_file = NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create file in: {}", dir.display()))?;
+    write(temp_file.as_file_mut())?;
+    temp_file.as_file().sync_all()?;
+    temp_file
+        .persist(path)
+        .with_context(|| format!("failed to write file: {}", path.display()))?;
     Ok(())
 }
 
-fn process_csv<R: std::io::Read>(processor: &Processor, input: R) -> anyhow::Result<()> {
-    let mut reader = ReaderBuilder::new()
-        .trim(csv::Trim::All) // Trims leading and trailing whitespace
-        .from_reader(input);
-    for result in reader.deserialize() {
-        let record: Transaction = result.context("failed to parse record from CSV")?;
-        processor.send_transaction(record)?;
+/// Process the input files, with checkpoints if requested. When resuming,
+/// the first `skip` records are assumed to be in the restored checkpoint.
+fn process_input(
+    args: &Args,
+    processor: &Processor,
+    options: &InputOptions,
+    skip: u64,
+) -> anyhow::Result<()> {
+    if args.stream {
+        if args.format != InputFormat::Csv {
+            return Err(anyhow::anyhow!("streaming is only supported for CSV input"));
+        }
+        return process_lines(processor, std::io::stdin().lock(), options)
+            .map(|_| ())
+            .context("failed to process stdin");
     }
-    Ok(())
+    // When resuming, keep checkpointing to the same file unless told otherwise.
+    let Some(checkpoint_path) = args.checkpoint.as_ref().or(args.resume.as_ref()) else {
+        return process_files(processor, &args.filenames, args.jobs, options);
+    };
+    let [filename] = args.filenames.as_slice() else {
+        return Err(anyhow::anyhow!("checkpoints require a single input file"));
+    };
+    if args.format != InputFormat::Csv {
+        return Err(anyhow::anyhow!(
+            "checkpoints are only supported for CSV input"
+        ));
+    }
+
+    let checkpoint = CheckpointOptions::builder()
+        .path(checkpoint_path)
+        .interval(args.checkpoint_interval)
+        .build();
+    let f = File::open(filename)
+        .with_context(|| format!("failed to open file: {}", filename.display()))?;
+    process_csv_checkpointed(processor, f, options, &checkpoint, skip)
+        .with_context(|| format!("failed to process file: {}", filename.display()))
+}
+
+fn run<W: Write>(args: &Args, writer: W) -> anyhow::Result<ExitCode> {
+    let mut database = AccountDatabase::with_precision(args.precision);
+    if let Some(fee_account) = args.fee_account {
+        database = database.with_fee_account(fee_account);
+    }
+    let apply_options = ApplyOptions::builder()
+        .idempotent_disputes(args.idempotent_disputes)
+        .maybe_dust_threshold(args.sweep_dust)
+        .maybe_withdrawal_fee(args.withdrawal_fee)
+        .maybe_daily_withdrawal_limit(args.daily_withdrawal_limit)
+        .maybe_max_history(args.max_history)
+        .maybe_max_precision(args.max_precision)
+        .type_precision(args.type_precision.iter().copied().collect())
+        .balances_only(args.balances_only)
+        .freeze_on_partial_chargeback(args.freeze_on_partial_chargeback)
+        .build();
+    let mut config = ProcessorConfig::builder()
+        .workers(args.workers)
+        .maybe_reorder_window(args.reorder_window)
+        .maybe_max_in_flight(args.max_in_flight)
+        .maybe_max_failures(args.max_failures)
+        .reserved_clients(args.reserved_clients.iter().copied().collect())
+        .apply_options(apply_options)
+        .build();
+
+    // The dead letter file is truncated up front, so it can't also be the
+    // file being replayed.
+    if let (Some(dead_letters), Some(replay)) = (&args.dead_letters, &args.replay_failures) {
+        let same_file = match (fs::canonicalize(dead_letters), fs::canonicalize(replay)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => dead_letters == replay,
+        };
+        if same_file {
+            return Err(anyhow::anyhow!(
+                "--dead-letters and --replay-failures must be different files"
+            ));
+        }
+    }
+
+    let dead_letter_writer = match &args.dead_letters {
+        Some(path) => {
+            let f = File::create(path)
+                .with_context(|| format!("failed to create file: {}", path.display()))?;
+            let (tx, rx) = channel();
+            config.dead_letters = Some(tx);
+            Some(thread::spawn(move || {
+                write_dead_letters(rx, BufWriter::new(f))
+            }))
+        }
+        None => None,
+    };
+    let event_log_writer = match &args.event_log {
+        Some(path) => {
+            let f = File::create(path)
+                .with_context(|| format!("failed to create file: {}", path.display()))?;
+            let (tx, rx) = channel();
+            config.event_log = Some(tx);
+            let format = args.event_log_format;
+            Some(thread::spawn(move || {
+                write_event_log(rx, BufWriter::new(f), format)
+            }))
+        }
+        None => None,
+    };
+
+    if let Some(path) = &args.seed {
+        let f =
+            File::open(path).with_context(|| format!("failed to open file: {}", path.display()))?;
+        load_seed(&database, f)
+            .with_context(|| format!("failed to load seed file: {}", path.display()))?;
+    }
+
+    let mut skip = 0;
+    if let Some(path) = &args.resume {
+        let checkpoint = Checkpoint::load(path)?;
+        eprintln!("Resuming after {} record(s)", checkpoint.records);
+        skip = checkpoint.records;
+        checkpoint.restore(&database);
+    }
+
+    let processor = Processor::with_config(database.clone(), config);
+    let input_options = InputOptions::builder()
+        .format(args.format)
+        .trim(args.trim)
+        .delimiter(args.delimiter)
+        .mmap(args.mmap)
+        .type_aliases(args.type_alias.iter().cloned().collect())
+        .skip_bad_rows(args.skip_bad_rows)
+        .maybe_limit(args.limit)
+        .build();
+    let result = process_input(args, &processor, &input_options, skip).and_then(|_| {
+        match &args.replay_failures {
+            // Dead letters are always written as CSV.
+            Some(path) => process_file(&processor, path, &InputOptions::default()),
+            None => Ok(()),
+        }
+    });
+    let metrics = processor.close();
+    if let Some(handle) = dead_letter_writer {
+        handle.join().expect("dead letter writer panicked")?;
+    }
+    if let Some(handle) = event_log_writer {
+        handle.join().expect("event log writer panicked")?;
+    }
+    // Hitting the failure limit is reported after writing the output, so
+    // that it reflects what was processed before stopping.
+    let limit_reached: Option<anyhow::Error> = match result {
+        Err(e) if e.is::<FailureLimitReached>() => Some(e),
+        Err(e) => return Err(e),
+        // The workers may only have stopped after all input was sent.
+        Ok(()) => args
+            .max_failures
+            .filter(|&max| metrics.failed >= max)
+            .map(|max| FailureLimitReached(max).into()),
+    };
+
+    if args.check_conservation {
+        database.conservation_check()?;
+    }
+
+    let mut columns = match (&args.columns, args.wide) {
+        (Some(columns), _) => columns.clone(),
+        (None, true) => OutputColumn::WIDE.to_vec(),
+        (None, false) => OutputColumn::DEFAULT.to_vec(),
+    };
+    let aliases = match &args.aliases {
+        Some(path) => {
+            let f = File::open(path)
+                .with_context(|| format!("failed to open alias file: {}", path.display()))?;
+            if args.columns.is_none() {
+                columns.insert(1, OutputColumn::Name);
+            }
+            load_aliases(f)?
+        }
+        None => HashMap::new(),
+    };
+    let output_options = OutputOptions::builder()
+        .fixed_decimals(args.fixed_decimals)
+        .columns(columns)
+        .omit_inactive(args.omit_inactive)
+        .aliases(aliases)
+        .locked_format(args.locked_format)
+        .build();
+    match &args.output {
+        Some(path) => write_atomically(path, |f| {
+            database.output_data_buffered_with(f, &output_options)
+        })?,
+        None if args.pretty => database.output_table(writer, &output_options)?,
+        None => database.output_data_buffered_with(writer, &output_options)?,
+    }
+
+    if args.report {
+        eprint!("{metrics}{}", database.totals()?);
+    }
+
+    if let Some(e) = limit_reached {
+        return Err(e);
+    }
+
+    if args.strict && (metrics.failed > 0 || metrics.skipped_rows > 0) {
+        eprintln!(
+            "{} transaction(s) failed, {} row(s) skipped",
+            metrics.failed, metrics.skipped_rows
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, io::Cursor};
+    use std::io::Cursor;
 
     use super::*;
 
-    fn lines_sorted(input: &str) -> HashSet<String> {
-        input.lines().map(|x| x.to_string()).collect()
+    fn run_with(input: &str, extra_args: &[&str]) -> (ExitCode, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, input).unwrap();
+
+        let mut argv = vec!["money-project", path.to_str().unwrap()];
+        argv.extend(extra_args);
+        let args = Args::parse_from(argv);
+
+        let mut output = Cursor::new(Vec::new());
+        let code = run(&args, &mut output).unwrap();
+        (code, String::from_utf8(output.into_inner()).unwrap())
     }
 
     #[test]
-    fn test_example_data() {
+    fn test_tab_delimiter() {
+        let input = "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\n";
+        let (code, output) = run_with(input, &["--delimiter", "\\t"]);
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+
+        for delimiter in ["", ";;", "é"] {
+            let argv = ["money-project", "input.csv", "--delimiter", delimiter];
+            assert!(Args::try_parse_from(argv).is_err(), "{delimiter:?}");
+        }
+    }
+
+    #[test]
+    fn test_empty_input() {
+        for (input, format) in [
+            ("", "csv"),
+            ("type, client, tx, amount\n", "csv"),
+            ("", "toml"),
+        ] {
+            let (code, output) = run_with(input, &["--format", format]);
+            assert_eq!(code, ExitCode::SUCCESS);
+            assert_eq!(output, "client,available,held,total,locked\n");
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let args = Args::parse_from(["money-project", "does-not-exist.csv"]);
+        let err = run(&args, Vec::new()).expect_err("missing file");
+        assert_eq!(exit_code(&err), EXIT_IO_ERROR);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, "type, client, tx, amount\nbogus, 1, 1, 1.0").unwrap();
+        let args = Args::parse_from(["money-project", path.to_str().unwrap()]);
+        let err = run(&args, Vec::new()).expect_err("invalid input");
+        assert_eq!(exit_code(&err), EXIT_PARSE_ERROR);
+
+        assert_eq!(
+            exit_code(&anyhow::anyhow!("processor disconnected")),
+            EXIT_PROCESSING_ERROR
+        );
+    }
+
+    #[test]
+    fn test_output_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.csv");
+        let (code, stdout) = run_with(
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0",
+            &["--output", path.to_str().unwrap()],
+        );
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(stdout.is_empty());
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_atomically_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.csv");
+        let err = write_atomically(&path, |f| {
+            f.write_all(b"client,available,held,total,locked\n1,")?;
+            Err(anyhow::anyhow!("failed mid-write"))
+        })
+        .expect_err("write should fail");
+        assert_eq!(err.to_string(), "failed mid-write");
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // An existing file is left untouched.
+        std::fs::write(&path, "previous").unwrap();
+        write_atomically(&path, |_| Err(anyhow::anyhow!("failed"))).unwrap_err();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+    }
+
+    #[test]
+    fn test_write_atomically_temp_name() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A neighbouring file with the old temporary name is not touched.
+        let neighbour = dir.path().join("output.tmp");
+        std::fs::write(&neighbour, "neighbour").unwrap();
+        let path = dir.path().join("output.csv");
+        write_atomically(&path, |f| Ok(f.write_all(b"output")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "output");
+        assert_eq!(std::fs::read_to_string(&neighbour).unwrap(), "neighbour");
+
+        // Nor is a target that already has that name.
+        write_atomically(&neighbour, |f| Ok(f.write_all(b"replaced")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&neighbour).unwrap(), "replaced");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_strict() {
         let input = r#"type, client, tx, amount
 deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0
-deposit, 1, 3, 2.0
-withdrawal, 1, 4, 1.5
-withdrawal, 2, 5, 3.0"#;
+withdrawal, 1, 2, 5.0"#;
+
+        let (code, output) = run_with(input, &[]);
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(output.contains("1,1.0,0,1.0,false"));
 
-        let database = AccountDatabase::default();
-        let processor = Processor::new(database.clone());
-        process_csv(&processor, Cursor::new(input)).unwrap();
-        processor.close();
+        // Output is still produced in strict mode.
+        let (code, output) = run_with(input, &["--strict"]);
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(output.contains("1,1.0,0,1.0,false"));
 
+        let (code, _) = run_with(
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0",
+            &["--strict"],
+        );
+        assert_eq!(code, ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn test_limit_zero() {
+        let (code, output) = run_with(
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0",
+            &["--limit", "0"],
+        );
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(output, "client,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn test_skip_bad_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letters = dir.path().join("dead_letters.csv");
+        let (code, output) = run_with(
+            "type, client, tx, amount\nrefund, 1, 1, 1.0\ndeposit, 1, 2, 1.0",
+            &[
+                "--skip-bad-rows",
+                "--dead-letters",
+                dead_letters.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(output.contains("1,1.0,0,1.0,false"));
+        let written = std::fs::read_to_string(&dead_letters).unwrap();
+        assert!(
+            written.contains("refund,1,1,1.0,,,unknown transaction type: refund"),
+            "{written}"
+        );
+    }
+
+    #[test]
+    fn test_max_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+withdrawal, 1, 3, 5.0
+deposit, 1, 4, 1.0";
+        std::fs::write(&path, input).unwrap();
+
+        let args = Args::parse_from([
+            "money-project",
+            path.to_str().unwrap(),
+            "--max-failures",
+            "2",
+        ]);
+        let mut output = Cursor::new(Vec::new());
+        let err = run(&args, &mut output).expect_err("failure limit");
+        assert!(err.is::<FailureLimitReached>());
+        assert_eq!(exit_code(&err), EXIT_PROCESSING_ERROR);
+        // Only the first deposit was applied before stopping.
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0,1.0,false\n"
+        );
+
+        let (code, _) = run_with(input, &["--max-failures", "3"]);
+        assert_eq!(code, ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn test_event_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = dir.path().join("events.ndjson");
+        let (code, _) = run_with(
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0",
+            &[
+                "--event-log",
+                event_log.to_str().unwrap(),
+                "--event-log-format",
+                "ndjson",
+            ],
+        );
+        assert_eq!(code, ExitCode::SUCCESS);
+        let written = std::fs::read_to_string(&event_log).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(r#""type":"deposit""#), "{written}");
+    }
+
+    #[test]
+    fn test_withdrawal_fee_args() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["money-project", "input.csv"];
+            argv.extend(args);
+            Args::try_parse_from(argv)
+        };
+        let args = parse(&["--withdrawal-fee", "1.5", "--fee-account", "99"]).unwrap();
+        assert_eq!(args.withdrawal_fee, Some("1.5".parse().unwrap()));
+
+        // Negative fees, and fees that would go nowhere, are rejected.
+        assert!(parse(&["--withdrawal-fee", "-1.5", "--fee-account", "99"]).is_err());
+        assert!(parse(&["--withdrawal-fee", "1.5"]).is_err());
+        assert!(parse(&["--fee-account", "99"]).is_err());
+    }
+
+    #[test]
+    fn test_replay_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        let dead_letters = dir.path().join("dead_letters.csv");
+
+        // The dispute arrives before its deposit, so it fails.
+        std::fs::write(
+            &input,
+            "type, client, tx, amount\ndispute, 1, 1,\ndeposit, 1, 1, 10.0",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "money-project",
+            input.to_str().unwrap(),
+            "--dead-letters",
+            dead_letters.to_str().unwrap(),
+        ]);
         let mut output = Cursor::new(Vec::new());
-        database.output_data(&mut output).unwrap();
-        database.verify_all_accounts();
+        run(&args, &mut output).unwrap();
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        assert!(output.contains("1,10.0,0,10.0,false"));
+        let written = std::fs::read_to_string(&dead_letters).unwrap();
+        assert!(written.contains("dispute,1,1,"));
+        assert!(written.contains("disputed transaction not found"));
 
-        let expected_output = r#"client,available,held,total,locked
-1,1.5,0,1.5,false
-2,2.0,0,2.0,false"#;
+        // Replaying the failure after the deposit now succeeds.
+        let args = Args::parse_from([
+            "money-project",
+            input.to_str().unwrap(),
+            "--replay-failures",
+            dead_letters.to_str().unwrap(),
+        ]);
+        let mut output = Cursor::new(Vec::new());
+        run(&args, &mut output).unwrap();
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        assert!(output.contains("1,0.0,10.0,10.0,false"), "{output}");
 
+        // Replaying into the same dead letter file would truncate it first.
+        let same = dir.path().join(".").join("dead_letters.csv");
+        let args = Args::parse_from([
+            "money-project",
+            input.to_str().unwrap(),
+            "--dead-letters",
+            same.to_str().unwrap(),
+            "--replay-failures",
+            dead_letters.to_str().unwrap(),
+        ]);
+        let err = run(&args, Vec::new()).expect_err("same file");
         assert_eq!(
-            lines_sorted(&String::from_utf8(output.into_inner()).unwrap()),
-            lines_sorted(expected_output)
+            err.to_string(),
+            "--dead-letters and --replay-failures must be different files"
         );
+        assert_eq!(std::fs::read_to_string(&dead_letters).unwrap(), written);
     }
 }
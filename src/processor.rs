@@ -1,53 +1,515 @@
 use std::{
-    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use chrono::{DateTime, Utc};
+use csv::StringRecord;
 
 use crate::{
-    account::{Account, AccountDatabase},
-    transaction::{Transaction, TransactionType},
+    account::{Account, AccountDatabase, AccountSummary, ClientId},
+    clock::{Clock, SystemClock},
+    currency::{Currency, NonNegativeCurrency},
+    dead_letter::{DeadLetter, DeadLetterEntry},
+    error::{FailureLimitReached, TransactionError},
+    store::{AccountStore, MemoryStore},
+    transaction::{Transaction, TransactionId, TransactionType},
 };
 
+/// How often a blocked send retries when backpressure monitoring is enabled.
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 #[derive(Debug)]
 pub enum Message {
     End,
     Transaction(Transaction),
+    /// A transaction that was rejected before it was sent, to be counted and
+    /// dead-lettered by the worker like any other failure.
+    Rejected(Transaction, anyhow::Error),
+    /// Acknowledged once every earlier message has been handled.
+    Sync(Sender<()>),
 }
 
-pub struct Processor {
-    tx: SyncSender<Message>,
-    handle: Option<JoinHandle<anyhow::Result<()>>>,
+/// Callback invoked with the time spent blocked when a send exceeds the
+/// backpressure threshold.
+pub type BackpressureObserver = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// Custom check run (with the account locked) before a transaction is
+/// applied. Returning an error rejects the transaction.
+pub type Validator =
+    Arc<dyn Fn(&Transaction, &Account) -> Result<(), TransactionError> + Send + Sync>;
+
+#[non_exhaustive]
+#[derive(Clone, bon::Builder)]
+pub struct ProcessorConfig {
+    /// Number of processing threads. Clients are assigned to a thread by id,
+    /// so transactions for one client are always applied in the order sent.
+    #[builder(default = 1)]
+    pub workers: usize,
+    /// Maximum number of messages queued (per worker) before senders block.
+    #[builder(default = 100)]
+    pub channel_capacity: usize,
+    /// Maximum number of transactions queued or being applied across all
+    /// workers (at least 1). Senders block once it is reached, however the
+    /// transactions are spread between workers.
+    pub max_in_flight: Option<usize>,
+    /// Warn if `send_transaction` blocks for longer than this.
+    pub backpressure_threshold: Option<Duration>,
+    /// Called (in addition to logging) when the backpressure threshold is exceeded.
+    pub backpressure_observer: Option<BackpressureObserver>,
+    /// Reject deposits and withdrawals unless their transaction id is greater
+    /// than every id seen before. Disputes etc. reuse prior ids so are exempt.
+    ///
+    /// Ids are checked in the order transactions are sent (after any
+    /// reordering), whatever the number of workers. An id counts as seen once
+    /// it passes this check, even if the transaction is then rejected when it
+    /// is applied, e.g. for insufficient funds.
+    #[builder(default)]
+    pub monotonic_tx_ids: bool,
+    /// Reject every transaction for these clients, e.g. if the feed uses
+    /// client 0 as a sentinel.
+    #[builder(default)]
+    pub reserved_clients: HashSet<ClientId>,
+    /// Failed transactions are sent here (in addition to being logged).
+    pub dead_letters: Option<Sender<DeadLetter>>,
+    /// Successfully applied transactions are sent here, in the order they
+    /// were applied. With more than one worker, transactions for different
+    /// clients may interleave differently from the input.
+    pub event_log: Option<Sender<Transaction>>,
+    /// After each applied transaction, the client's new balances are sent
+    /// here, e.g. to update a live view. Fees credited to the fee account
+    /// are not reported.
+    pub balance_snapshots: Option<Sender<AccountSummary>>,
+    /// Options used when applying each transaction.
+    #[builder(default)]
+    pub apply_options: ApplyOptions,
+    /// Hold back up to this many timestamped transactions and send them in
+    /// timestamp order, to correct a feed that is slightly out of order.
+    /// Equal timestamps are ordered by transaction id. Transactions without a
+    /// timestamp sort after those with one, in arrival order.
+    pub reorder_window: Option<usize>,
+    /// Stop once this many transactions have failed, e.g. because the input
+    /// is corrupt. Queued transactions are discarded and further sends fail
+    /// with `FailureLimitReached`. With more than one worker, a few more
+    /// transactions may fail before every worker has stopped.
+    pub max_failures: Option<u64>,
 }
 
-impl Processor {
-    pub fn new(database: AccountDatabase) -> Self {
-        let (tx, rx) = sync_channel(100);
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
 
-        let handle = thread::spawn(move || {
-            process_transactions(database, rx)?;
-            Ok(())
-        });
+/// Snapshot of the processor's transaction counters.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessorMetrics {
+    /// Transactions received by the processing thread.
+    pub received: u64,
+    /// Transactions successfully applied.
+    pub applied: u64,
+    /// Transactions that were rejected.
+    pub failed: u64,
+    /// Gaps detected in transaction sequence numbers.
+    pub sequence_gaps: u64,
+    /// Input rows that could not be parsed and were skipped.
+    pub skipped_rows: u64,
+}
+
+impl Display for ProcessorMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Transactions received: {}", self.received)?;
+        writeln!(f, "Transactions applied:  {}", self.applied)?;
+        writeln!(f, "Transactions failed:   {}", self.failed)?;
+        writeln!(f, "Sequence gaps:         {}", self.sequence_gaps)?;
+        writeln!(f, "Rows skipped:          {}", self.skipped_rows)
+    }
+}
+
+/// State shared between the processor and its worker threads.
+#[derive(Debug, Default)]
+struct Shared {
+    received: AtomicU64,
+    applied: AtomicU64,
+    failed: AtomicU64,
+    sequence_gaps: AtomicU64,
+    skipped_rows: AtomicU64,
+    /// Set by `Processor::abort` to stop the workers without draining.
+    aborted: AtomicBool,
+    /// Transactions sent but not yet handled (only with `max_in_flight`).
+    in_flight: Mutex<usize>,
+    /// Signalled whenever `in_flight` goes down.
+    in_flight_released: Condvar,
+    /// Last sequence number seen, for transactions that have one.
+    last_sequence: Mutex<Option<u64>>,
+}
+
+impl Shared {
+    fn release_in_flight(&self) {
+        let mut in_flight = self.in_flight.lock().expect("lock poisoned");
+        *in_flight = in_flight.saturating_sub(1);
+        self.in_flight_released.notify_one();
+    }
+    fn snapshot(&self) -> ProcessorMetrics {
+        ProcessorMetrics {
+            received: self.received.load(Ordering::SeqCst),
+            applied: self.applied.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+            sequence_gaps: self.sequence_gaps.load(Ordering::SeqCst),
+            skipped_rows: self.skipped_rows.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Applies transactions to an `AccountDatabase` on background worker threads.
+///
+/// Ordering: each client is assigned to exactly one worker (by
+/// `ClientId::shard`), and each worker applies its queue in order, so
+/// transactions for the same client are always applied in the order they
+/// were sent, whatever the number of workers. There is no ordering between
+/// different clients. With `reorder_window`, the order sent is the
+/// reordered one.
+pub struct Processor<S: AccountStore = MemoryStore> {
+    /// One sender per worker thread.
+    senders: Vec<SyncSender<Message>>,
+    handles: Vec<JoinHandle<anyhow::Result<()>>>,
+    config: Arc<ProcessorConfig>,
+    database: AccountDatabase<S>,
+    shared: Arc<Shared>,
+    /// Transactions held back for reordering (see `reorder_window`).
+    reorder_buffer: Mutex<ReorderBuffer>,
+    /// Highest deposit/withdrawal id sent so far (monotonic mode only).
+    highest_tx_id: Mutex<Option<TransactionId>>,
+}
+
+/// Pending transactions, in the order they will be sent.
+#[derive(Default)]
+struct ReorderBuffer {
+    pending: BTreeMap<ReorderKey, Transaction>,
+    arrivals: u64,
+}
+
+/// Sort key for the reorder buffer. The arrival count makes every key unique
+/// and keeps the order stable for otherwise identical keys.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ReorderKey {
+    Timestamped(DateTime<Utc>, TransactionId, u64),
+    /// Sorts after all timestamped transactions.
+    Untimestamped(u64),
+}
+
+impl<S: AccountStore> Processor<S> {
+    pub fn new(database: AccountDatabase<S>) -> Self {
+        Self::with_config(database, ProcessorConfig::default())
+    }
+
+    pub fn with_config(database: AccountDatabase<S>, config: ProcessorConfig) -> Self {
+        let config = Arc::new(config);
+        let shared = Arc::new(Shared::default());
+
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..config.workers.max(1) {
+            let (tx, rx) = sync_channel(config.channel_capacity);
+            let worker = Worker {
+                database: database.clone(),
+                config: config.clone(),
+                shared: shared.clone(),
+            };
+            senders.push(tx);
+            handles.push(thread::spawn(move || {
+                process_transactions(worker, rx)?;
+                Ok(())
+            }));
+        }
 
         Self {
-            tx,
-            handle: Some(handle),
+            senders,
+            handles,
+            config,
+            database,
+            shared,
+            reorder_buffer: Mutex::default(),
+            highest_tx_id: Mutex::default(),
         }
     }
 
     pub fn send_transaction(&self, transaction: Transaction) -> anyhow::Result<()> {
-        self.tx
-            .send(Message::Transaction(transaction))
-            .context("failed to send transaction")
+        self.check_failure_limit()?;
+        if self.shared.aborted.load(Ordering::SeqCst) {
+            return Err(anyhow::anyhow!(
+                "failed to send transaction: processor aborted"
+            ));
+        }
+
+        let Some(window) = self.config.reorder_window else {
+            return self.dispatch(transaction);
+        };
+
+        let mut buffer = self.reorder_buffer.lock().expect("lock poisoned");
+        let arrival = buffer.arrivals;
+        buffer.arrivals += 1;
+        let key = match transaction.timestamp {
+            Some(timestamp) => {
+                ReorderKey::Timestamped(timestamp, transaction.transaction_id, arrival)
+            }
+            None => ReorderKey::Untimestamped(arrival),
+        };
+        buffer.pending.insert(key, transaction);
+        while buffer.pending.len() > window {
+            let (_, earliest) = buffer.pending.pop_first().expect("buffer is not empty");
+            self.dispatch(earliest)?;
+        }
+        Ok(())
     }
 
-    pub fn close(mut self) {
-        if let Some(handle) = self.handle.take() {
-            if self.tx.send(Message::End).is_err() {
-                tracing::error!("failed to send End message to processor");
+    fn flush_reorder_buffer(&self, buffer: &mut ReorderBuffer) -> anyhow::Result<()> {
+        while let Some((_, transaction)) = buffer.pending.pop_first() {
+            self.dispatch(transaction)?;
+        }
+        Ok(())
+    }
+
+    /// Record an input row that could not be parsed, sending it to the dead
+    /// letters (if configured) exactly as it was read.
+    pub fn skip_row(&self, headers: &StringRecord, row: &StringRecord, reason: String) {
+        self.shared.skipped_rows.fetch_add(1, Ordering::SeqCst);
+        tracing::warn!("skipping row: {reason}");
+        if let Some(dead_letters) = &self.config.dead_letters {
+            let dead_letter = DeadLetter {
+                entry: DeadLetterEntry::Row {
+                    headers: headers.clone(),
+                    row: row.clone(),
+                },
+                reason,
+            };
+            if dead_letters.send(dead_letter).is_err() {
+                tracing::error!("failed to send dead letter");
+            }
+        }
+    }
+
+    fn check_failure_limit(&self) -> anyhow::Result<()> {
+        match self.config.max_failures {
+            Some(max) if self.shared.failed.load(Ordering::SeqCst) >= max => {
+                Err(FailureLimitReached(max).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until there is room for another transaction under
+    /// `max_in_flight`, then count it.
+    fn acquire_in_flight(&self) -> anyhow::Result<()> {
+        let Some(max) = self.config.max_in_flight else {
+            return Ok(());
+        };
+
+        let mut in_flight = self.shared.in_flight.lock().expect("lock poisoned");
+        while *in_flight >= max.max(1) {
+            // The workers stop releasing once aborted.
+            if self.shared.aborted.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!(
+                    "failed to send transaction: processor aborted"
+                ));
             }
+            in_flight = self
+                .shared
+                .in_flight_released
+                .wait_timeout(in_flight, BACKPRESSURE_POLL_INTERVAL)
+                .expect("lock poisoned")
+                .0;
+        }
+        *in_flight += 1;
+        Ok(())
+    }
+
+    /// Send a transaction to the worker for its client.
+    ///
+    /// The checks that don't involve the account are made here, in the order
+    /// sent, rather than by the workers.
+    fn dispatch(&self, transaction: Transaction) -> anyhow::Result<()> {
+        self.acquire_in_flight()?;
+        let tx = &self.senders[transaction.client_id.shard(self.senders.len())];
+        let message = match self
+            .check_reserved(&transaction)
+            .and_then(|_| self.check_monotonic(&transaction))
+        {
+            Ok(()) => Message::Transaction(transaction),
+            Err(e) => Message::Rejected(transaction, e),
+        };
+        let result = match self.config.backpressure_threshold {
+            Some(threshold) => self.send_timed(tx, message, threshold),
+            None => tx.send(message).context("failed to send transaction"),
+        };
+        if result.is_err() && self.config.max_in_flight.is_some() {
+            self.shared.release_in_flight();
+        }
+        // The worker stops receiving once it reaches the failure limit, so
+        // report that instead of the disconnect.
+        result.or_else(|e| self.check_failure_limit().and(Err(e)))
+    }
+
+    fn check_reserved(&self, transaction: &Transaction) -> anyhow::Result<()> {
+        if self
+            .config
+            .reserved_clients
+            .contains(&transaction.client_id)
+        {
+            return Err(anyhow::anyhow!(
+                "client id {} is reserved",
+                transaction.client_id
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_monotonic(&self, transaction: &Transaction) -> anyhow::Result<()> {
+        if !self.config.monotonic_tx_ids
+            || !matches!(
+                transaction.transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            return Ok(());
+        }
+
+        let mut highest_tx_id = self.highest_tx_id.lock().expect("lock poisoned");
+        if let Some(highest) = *highest_tx_id {
+            if transaction.transaction_id <= highest {
+                return Err(anyhow::anyhow!(
+                    "transaction id {} is not greater than previous id {highest}",
+                    transaction.transaction_id
+                ));
+            }
+        }
+        *highest_tx_id = Some(transaction.transaction_id);
+        Ok(())
+    }
+
+    /// Send a message, polling with `try_send` so that we can report when the
+    /// processing thread is not keeping up.
+    fn send_timed(
+        &self,
+        tx: &SyncSender<Message>,
+        mut message: Message,
+        threshold: Duration,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let mut warned = false;
+        loop {
+            match tx.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(m)) => {
+                    message = m;
+                    let blocked = start.elapsed();
+                    if !warned && blocked >= threshold {
+                        warned = true;
+                        tracing::warn!("send_transaction blocked for {blocked:?}");
+                        if let Some(observer) = &self.config.backpressure_observer {
+                            observer(blocked);
+                        }
+                    }
+                    thread::sleep(BACKPRESSURE_POLL_INTERVAL);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(anyhow::anyhow!(
+                        "failed to send transaction: processor disconnected"
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Wait until every transaction sent so far has been applied (or
+    /// rejected), including any held back for reordering.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        self.flush_reorder_buffer(&mut self.reorder_buffer.lock().expect("lock poisoned"))?;
 
+        let (ack_tx, ack_rx) = channel();
+        for tx in &self.senders {
+            tx.send(Message::Sync(ack_tx.clone()))
+                .context("failed to send sync message")?;
+        }
+        drop(ack_tx);
+        for _ in &self.senders {
+            ack_rx.recv().context("processor stopped before syncing")?;
+        }
+        Ok(())
+    }
+
+    /// The database that transactions are applied to.
+    pub fn database(&self) -> &AccountDatabase<S> {
+        &self.database
+    }
+
+    /// Current transaction counters. These are only final after `close()`.
+    pub fn metrics(&self) -> ProcessorMetrics {
+        self.shared.snapshot()
+    }
+
+    /// Stop processing as soon as possible, discarding any queued
+    /// transactions. Call `close()` afterwards to wait for the workers.
+    ///
+    /// A transaction that is already being applied will still complete.
+    pub fn abort(&self) {
+        self.shared.aborted.store(true, Ordering::SeqCst);
+        self.reorder_buffer
+            .lock()
+            .expect("lock poisoned")
+            .pending
+            .clear();
+        self.wake_workers();
+    }
+
+    /// Wake any idle workers after aborting. Busy ones will see the flag
+    /// after their current transaction, so a full queue doesn't matter.
+    fn wake_workers(&self) {
+        for tx in &self.senders {
+            tx.try_send(Message::End).ok();
+        }
+    }
+
+    /// Drain all queued transactions and stop the processing thread.
+    pub fn close(mut self) -> ProcessorMetrics {
+        self.shutdown();
+        self.metrics()
+    }
+
+    /// Close the processor and then verify the integrity of every account.
+    pub fn close_and_verify(mut self) -> anyhow::Result<ProcessorMetrics> {
+        self.shutdown();
+        self.database.check_invariants()?;
+        Ok(self.metrics())
+    }
+
+    fn shutdown(&mut self) {
+        if !self.shared.aborted.load(Ordering::SeqCst) {
+            let mut buffer = std::mem::take(self.reorder_buffer.get_mut().expect("lock poisoned"));
+            if let Err(e) = self.flush_reorder_buffer(&mut buffer) {
+                tracing::error!("failed to flush reordered transactions: {e:#}");
+            }
+
+            for tx in &self.senders {
+                if tx.send(Message::End).is_err() {
+                    tracing::error!("failed to send End message to processor");
+                }
+            }
+        } else {
+            // A worker may have aborted after reaching `max_failures`.
+            self.wake_workers();
+        }
+
+        for handle in self.handles.drain(..) {
             if let Err(e) = handle.join() {
                 tracing::error!("failed to join processor thread: {e:#?}");
             }
@@ -56,13 +518,17 @@ impl Processor {
 }
 
 /// Process transactions in a loop.
-pub fn process_transactions(
-    database: AccountDatabase,
+fn process_transactions<S: AccountStore>(
+    worker: Worker<S>,
     rx: Receiver<Message>,
 ) -> anyhow::Result<()> {
     loop {
         let message = rx.recv().context("failed to receive message")?;
         tracing::debug!("Received message: {message:#?}");
+        if worker.shared.aborted.load(Ordering::SeqCst) {
+            tracing::debug!("processor aborted. shutting down...");
+            return Ok(());
+        }
 
         match message {
             Message::End => {
@@ -70,12 +536,115 @@ pub fn process_transactions(
                 return Ok(());
             }
             Message::Transaction(t) => {
-                let account_mutex = database.account(t.client_id);
-                let mut account = account_mutex.lock().expect("lock poisoned");
-                if let Err(e) = apply_transaction(t, &mut account) {
-                    // Failed transactions could be sent to a queue for further processing.
+                worker.handle_transaction(t, None);
+                if worker.config.max_in_flight.is_some() {
+                    worker.shared.release_in_flight();
+                }
+            }
+            Message::Rejected(t, e) => {
+                worker.handle_transaction(t, Some(e));
+                if worker.config.max_in_flight.is_some() {
+                    worker.shared.release_in_flight();
+                }
+            }
+            Message::Sync(ack) => {
+                ack.send(()).ok();
+            }
+        }
+    }
+}
+
+/// State owned by the processing thread.
+struct Worker<S: AccountStore> {
+    database: AccountDatabase<S>,
+    config: Arc<ProcessorConfig>,
+    shared: Arc<Shared>,
+}
+
+impl<S: AccountStore> Worker<S> {
+    /// Apply a transaction, unless it was already rejected when sent.
+    fn handle_transaction(&self, transaction: Transaction, rejected: Option<anyhow::Error>) {
+        self.shared.received.fetch_add(1, Ordering::SeqCst);
+        self.check_sequence(&transaction);
+        if let Some(e) = rejected {
+            self.reject(transaction, e);
+            return;
+        }
+
+        // Only keep a copy if there is somewhere to send it afterwards.
+        let copy = (self.config.dead_letters.is_some() || self.config.event_log.is_some())
+            .then(|| transaction.clone());
+        match self
+            .database
+            .apply_and_snapshot(transaction, &self.config.apply_options)
+        {
+            Ok(summary) => {
+                self.shared.applied.fetch_add(1, Ordering::SeqCst);
+                if let Some(snapshots) = &self.config.balance_snapshots {
+                    if snapshots.send(summary).is_err() {
+                        tracing::error!("failed to send balance snapshot");
+                    }
+                }
+                if let (Some(event_log), Some(transaction)) = (&self.config.event_log, copy) {
+                    if event_log.send(transaction).is_err() {
+                        tracing::error!("failed to send to event log");
+                    }
+                }
+            }
+            Err(e) => match copy {
+                Some(transaction) => self.reject(transaction, e.into()),
+                None => {
+                    self.record_failure();
                     tracing::error!("transaction failed: {e:#}");
                 }
+            },
+        }
+    }
+
+    /// Warn if sequence numbers were skipped. Gaps are only reported, since
+    /// the missing transactions may simply have been filtered upstream.
+    ///
+    /// With more than one worker, transactions can arrive out of order, so
+    /// a gap may be reported for a transaction that turns up later.
+    fn check_sequence(&self, transaction: &Transaction) {
+        let Some(sequence) = transaction.sequence else {
+            return;
+        };
+
+        let mut last_sequence = self.shared.last_sequence.lock().expect("lock poisoned");
+        if let Some(last) = *last_sequence {
+            if sequence > last + 1 {
+                self.shared.sequence_gaps.fetch_add(1, Ordering::SeqCst);
+                tracing::warn!(
+                    "sequence gap before tx {}: expected {}, got {sequence} \
+                     (transactions may have been dropped)",
+                    transaction.transaction_id,
+                    last + 1
+                );
+            }
+        }
+        *last_sequence = Some(last_sequence.map_or(sequence, |last| last.max(sequence)));
+    }
+
+    /// Count a failed transaction, aborting if that reaches `max_failures`.
+    fn record_failure(&self) {
+        let failed = self.shared.failed.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.config.max_failures == Some(failed) {
+            tracing::error!("{}, aborting", FailureLimitReached(failed));
+            self.shared.aborted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn reject(&self, transaction: Transaction, error: anyhow::Error) {
+        self.record_failure();
+        tracing::error!("transaction failed: {error:#}");
+        if let Some(dead_letters) = &self.config.dead_letters {
+            let dead_letter = DeadLetter {
+                entry: DeadLetterEntry::Transaction(transaction),
+                reason: format!("{error:#}"),
+            };
+            if dead_letters.send(dead_letter).is_err() {
+                tracing::error!("failed to send dead letter");
             }
         }
     }
@@ -84,40 +653,186 @@ pub fn process_transactions(
 fn ensure_transaction_does_not_exist(
     transaction: &Transaction,
     account: &Account,
-) -> anyhow::Result<()> {
-    match account
-        .transactions
-        .contains_key(&transaction.transaction_id)
-    {
-        true => Err(anyhow::anyhow!("transaction id already exists")),
-        false => Ok(()),
+) -> Result<(), TransactionError> {
+    match account.transactions.get(&transaction.transaction_id) {
+        Some(existing) => Err(TransactionError::DuplicateTransaction {
+            original: existing.transaction_type,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn not_found(transaction: &Transaction) -> TransactionError {
+    TransactionError::TransactionNotFound {
+        client_id: transaction.client_id,
+        transaction_id: transaction.transaction_id,
+    }
+}
+
+/// Options that change how individual transactions are applied.
+#[non_exhaustive]
+#[derive(Clone, bon::Builder)]
+pub struct ApplyOptions {
+    /// Treat re-sending a dispute for an already disputed transaction as a
+    /// no-op rather than an error. Useful for at-least-once delivery.
+    #[builder(default)]
+    pub idempotent_disputes: bool,
+    /// If a withdrawal would leave a positive available balance below this
+    /// threshold, withdraw the full available balance instead.
+    pub dust_threshold: Option<Currency>,
+    /// Fee charged on every withdrawal, in addition to the amount withdrawn.
+    /// The recorded withdrawal amount includes the fee.
+    ///
+    /// The fee is deposited into the fee account when applied through an
+    /// `AccountDatabase` that has one (see `AccountDatabase::with_fee_account`).
+    /// Otherwise it leaves the totals along with the withdrawal.
+    pub withdrawal_fee: Option<NonNegativeCurrency>,
+    /// Compact each account's history down to this many recent entries.
+    /// See `Account::compact`.
+    pub max_history: Option<usize>,
+    /// Domain-specific validation. It runs after the checks that only look at
+    /// the transaction (such as precision), and is not called for
+    /// transactions those reject, but before any check against the account.
+    pub validator: Option<Validator>,
+    /// Reject amounts with more than this many decimal places, rather than
+    /// accepting (and later rounding) them.
+    pub max_precision: Option<u32>,
+    /// Per-type limits on decimal places, overriding `max_precision` for
+    /// the types listed.
+    #[builder(default)]
+    pub type_precision: HashMap<TransactionType, u32>,
+    /// Don't record transactions in the account history, for throughput when
+    /// only the final balances matter.
+    ///
+    /// Deposits and withdrawals are still cached, so disputes and duplicate
+    /// checks work as usual. Anything that replays the history (invariant
+    /// checks, `conservation_check`, statements) is not meaningful.
+    #[builder(default)]
+    pub balances_only: bool,
+    /// Reject a withdrawal if it would bring the client's withdrawals for
+    /// the day (UTC) above this, including any fee.
+    ///
+    /// Withdrawals without a timestamp are stamped with the current time
+    /// (from the database clock, when applied through `AccountDatabase`), so
+    /// that later ones can be summed. The sum only covers the history that
+    /// is still kept, so it is not meaningful with `balances_only`.
+    /// Reversed withdrawals don't count towards the limit.
+    pub daily_withdrawal_limit: Option<Currency>,
+    /// Source of the current time for withdrawals applied directly (not
+    /// through `AccountDatabase`) without a timestamp. See
+    /// `daily_withdrawal_limit`.
+    #[builder(default = Arc::new(SystemClock))]
+    pub clock: Arc<dyn Clock>,
+    /// Also freeze the account when a chargeback only charges back part of
+    /// the disputed amount. Charging back all of it always freezes.
+    #[builder(default)]
+    pub freeze_on_partial_chargeback: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        Self::builder().build()
     }
 }
 
-pub fn apply_transaction(transaction: Transaction, account: &mut Account) -> anyhow::Result<()> {
+pub fn apply_transaction(
+    transaction: Transaction,
+    account: &mut Account,
+) -> Result<(), TransactionError> {
+    apply_transaction_with(transaction, account, &ApplyOptions::default())
+}
+
+pub fn apply_transaction_with(
+    mut transaction: Transaction,
+    account: &mut Account,
+    options: &ApplyOptions,
+) -> Result<(), TransactionError> {
+    check_stateless(&transaction, options)?;
+
+    if let Some(validator) = &options.validator {
+        validator(&transaction, account)?;
+    }
+
     if account.is_locked() {
-        return Err(anyhow::anyhow!("account is locked"));
+        return Err(TransactionError::AccountLocked);
     }
 
-    if transaction.amount.is_negative() {
-        return Err(anyhow::anyhow!("transaction amount must not be negative"));
+    // A duplicate dispute is not recorded in the history, so that replaying
+    // the history still reproduces the account.
+    if options.idempotent_disputes
+        && transaction.transaction_type == TransactionType::Dispute
+        && account.disputes.contains_key(&transaction.transaction_id)
+    {
+        tracing::debug!(
+            "ignoring duplicate dispute for tx {}",
+            transaction.transaction_id
+        );
+        return Ok(());
     }
 
     match transaction.transaction_type {
         TransactionType::Deposit => apply_deposit(&transaction, account)?,
-        TransactionType::Withdrawal => apply_withdrawal(&transaction, account)?,
+        TransactionType::Withdrawal => apply_withdrawal(&mut transaction, account, options)?,
         TransactionType::Dispute => apply_dispute(&transaction, account)?,
         TransactionType::Resolve => apply_resolve(&transaction, account)?,
-        TransactionType::Chargeback => apply_chargeback(&transaction, account)?,
+        TransactionType::Chargeback => apply_chargeback(&mut transaction, account, options)?,
+        TransactionType::Reversal => apply_reversal(&mut transaction, account)?,
+        TransactionType::Adjustment => apply_adjustment(&transaction, account)?,
+    }
+    account.last_movement = match transaction.transaction_type {
+        TransactionType::Deposit | TransactionType::Withdrawal => Some(transaction.transaction_id),
+        _ => None,
+    };
+
+    if options.balances_only {
+        return Ok(());
     }
 
-    account.history.push(transaction);
+    account.record(transaction);
+    if let Some(max_history) = options.max_history {
+        account.compact(max_history);
+    }
 
     Ok(())
 }
 
-fn apply_deposit(transaction: &Transaction, account: &mut Account) -> anyhow::Result<()> {
-    ensure_transaction_does_not_exist(&transaction, &account)?;
+/// Checks that depend only on the transaction, not on the account.
+///
+/// `AccountDatabase::apply_with` runs these before looking up (and locking)
+/// the account, so that clearly invalid transactions don't contend for the
+/// lock. `apply_transaction_with` runs them first as well, so they are
+/// reported instead of `AccountLocked` for a locked account, and the
+/// validator is not called for transactions they reject.
+pub(crate) fn check_stateless(
+    transaction: &Transaction,
+    options: &ApplyOptions,
+) -> Result<(), TransactionError> {
+    let max_precision = options
+        .type_precision
+        .get(&transaction.transaction_type)
+        .copied()
+        .or(options.max_precision);
+    if let Some(max) = max_precision {
+        if transaction.amount.decimal_places() > max {
+            return Err(TransactionError::TooPrecise { max });
+        }
+    }
+
+    Ok(())
+}
+
+/// Deposits and withdrawals must move some money. Other transaction types
+/// carry no amount, so this is not part of the general checks.
+fn ensure_amount_is_positive(transaction: &Transaction) -> Result<(), TransactionError> {
+    match transaction.amount == Currency::ZERO {
+        true => Err(TransactionError::ZeroAmount),
+        false => Ok(()),
+    }
+}
+
+fn apply_deposit(transaction: &Transaction, account: &mut Account) -> Result<(), TransactionError> {
+    ensure_amount_is_positive(transaction)?;
+    ensure_transaction_does_not_exist(transaction, account)?;
 
     account.available += transaction.amount;
     account.total += transaction.amount;
@@ -129,11 +844,54 @@ fn apply_deposit(transaction: &Transaction, account: &mut Account) -> anyhow::Re
     Ok(())
 }
 
-fn apply_withdrawal(transaction: &Transaction, account: &mut Account) -> anyhow::Result<()> {
-    ensure_transaction_does_not_exist(&transaction, &account)?;
+fn apply_withdrawal(
+    transaction: &mut Transaction,
+    account: &mut Account,
+    options: &ApplyOptions,
+) -> Result<(), TransactionError> {
+    ensure_amount_is_positive(transaction)?;
+    ensure_transaction_does_not_exist(transaction, account)?;
+
+    // Record the amount including the fee, and the fee itself, so that the
+    // history replays correctly and a reversal knows what was charged.
+    if let Some(fee) = options.withdrawal_fee {
+        transaction.amount += fee.get();
+        transaction.fee = fee.get();
+    }
 
     if account.available < transaction.amount {
-        return Err(anyhow::anyhow!("insufficient funds"));
+        return Err(TransactionError::InsufficientFunds);
+    }
+
+    if let Some(threshold) = options.dust_threshold {
+        let remaining = account.available - transaction.amount;
+        if remaining > Currency::default() && remaining < threshold {
+            // Record the swept amount, so that the history replays correctly.
+            tracing::debug!(
+                "sweeping dust balance of {remaining} into tx {}",
+                transaction.transaction_id
+            );
+            transaction.amount = account.available;
+        }
+    }
+
+    if let Some(limit) = options.daily_withdrawal_limit {
+        let day = transaction
+            .timestamp
+            .get_or_insert_with(|| options.clock.now())
+            .date_naive();
+        let withdrawn_today = account
+            .history
+            .iter()
+            .filter(|x| {
+                x.transaction_type == TransactionType::Withdrawal
+                    && x.timestamp.is_some_and(|t| t.date_naive() == day)
+                    && !account.reversed.contains(&x.transaction_id)
+            })
+            .fold(Currency::ZERO, |sum, x| sum + x.amount);
+        if withdrawn_today + transaction.amount > limit {
+            return Err(TransactionError::DailyLimitExceeded { limit });
+        }
     }
 
     account.total -= transaction.amount;
@@ -145,63 +903,174 @@ fn apply_withdrawal(transaction: &Transaction, account: &mut Account) -> anyhow:
     Ok(())
 }
 
-fn apply_dispute(transaction: &Transaction, account: &mut Account) -> anyhow::Result<()> {
+/// Credit (or, if negative, debit) the account. The adjustment is cached
+/// like a deposit, so that its id can't be reused.
+fn apply_adjustment(
+    transaction: &Transaction,
+    account: &mut Account,
+) -> Result<(), TransactionError> {
+    if transaction.reference.is_none() {
+        return Err(TransactionError::MissingReference);
+    }
+    ensure_transaction_does_not_exist(transaction, account)?;
+
+    if (account.available + transaction.amount).is_negative() {
+        return Err(TransactionError::InsufficientFunds);
+    }
+
+    account.available += transaction.amount;
+    account.total += transaction.amount;
+
+    account
+        .transactions
+        .insert(transaction.transaction_id, transaction.clone());
+
+    Ok(())
+}
+
+fn apply_dispute(transaction: &Transaction, account: &mut Account) -> Result<(), TransactionError> {
     let disputed_transaction = account
         .transactions
         .get(&transaction.transaction_id)
-        .context("disputed transaction not found")?;
+        .ok_or_else(|| not_found(transaction))?;
+
+    if account.reversed.contains(&transaction.transaction_id) {
+        return Err(TransactionError::Reversed);
+    }
+
+    if account.disputes.contains_key(&transaction.transaction_id) {
+        return Err(TransactionError::AlreadyDisputed);
+    }
 
-    if account.disputes.contains(&transaction.transaction_id) {
-        return Err(anyhow::anyhow!("transaction already disputed"));
+    if !disputed_transaction.settled {
+        return Err(TransactionError::Unsettled);
     }
 
-    account.disputes.insert(transaction.transaction_id);
-    account.held += disputed_transaction.amount;
-    account.available -= disputed_transaction.amount;
+    if disputed_transaction.transaction_type == TransactionType::Adjustment {
+        return Err(TransactionError::AdjustmentDisputed);
+    }
+
+    let hold = disputed_transaction.amount;
+    account.disputes.insert(transaction.transaction_id, hold);
+    account.held += hold;
+    account.available -= hold;
     Ok(())
 }
 
-fn apply_resolve(transaction: &Transaction, account: &mut Account) -> anyhow::Result<()> {
-    if !account.disputes.contains(&transaction.transaction_id) {
-        return Err(anyhow::anyhow!("transaction not in dispute"));
+/// The amount held by the open dispute of `transaction`, checking that the
+/// account's held balance still covers it.
+fn dispute_hold(
+    transaction: &Transaction,
+    account: &Account,
+) -> Result<Currency, TransactionError> {
+    let hold = *account
+        .disputes
+        .get(&transaction.transaction_id)
+        .ok_or(TransactionError::NotDisputed)?;
+    if account.held < hold {
+        return Err(TransactionError::HeldBelowDispute {
+            held: account.held,
+            disputed: hold,
+        });
     }
+    Ok(hold)
+}
 
-    let disputed_transaction = account
+fn apply_resolve(transaction: &Transaction, account: &mut Account) -> Result<(), TransactionError> {
+    let hold = dispute_hold(transaction, account)?;
+    account.held -= hold;
+    account.available += hold;
+    // The transaction may be disputed again later.
+    account.disputes.remove(&transaction.transaction_id);
+
+    Ok(())
+}
+
+/// Undo the account's most recent transaction, which must be an undisputed
+/// deposit or withdrawal.
+///
+/// Reversing a withdrawal refunds the recorded amount less the fee it was
+/// charged, since the fee account keeps the fee. The amount reversed is
+/// recorded in the reversal, replacing any amount it was given.
+fn apply_reversal(
+    transaction: &mut Transaction,
+    account: &mut Account,
+) -> Result<(), TransactionError> {
+    let reversed = account
         .transactions
         .get(&transaction.transaction_id)
-        .context("disputed transaction not found")?;
+        .ok_or_else(|| not_found(transaction))?;
 
-    assert!(account.held >= disputed_transaction.amount);
-    account.held -= disputed_transaction.amount;
-    account.available += disputed_transaction.amount;
+    if account.disputes.contains_key(&transaction.transaction_id) {
+        return Err(TransactionError::AlreadyDisputed);
+    }
 
+    if account.last_movement != Some(transaction.transaction_id) {
+        return Err(TransactionError::NotMostRecent);
+    }
+
+    let amount = reversed.amount - reversed.fee;
+    match reversed.transaction_type {
+        TransactionType::Deposit => {
+            if account.available < amount {
+                return Err(TransactionError::InsufficientFunds);
+            }
+            account.available -= amount;
+            account.total -= amount;
+        }
+        _ => {
+            account.available += amount;
+            account.total += amount;
+        }
+    }
+    account.reversed.insert(transaction.transaction_id);
+    transaction.amount = amount;
     Ok(())
 }
 
-fn apply_chargeback(transaction: &Transaction, account: &mut Account) -> anyhow::Result<()> {
-    if !account.disputes.contains(&transaction.transaction_id) {
-        return Err(anyhow::anyhow!("transaction not in dispute"));
+/// Charge back a disputed transaction. If the chargeback has an amount, only
+/// that much is charged back and the rest of the disputed amount is returned
+/// to available, closing the dispute either way.
+///
+/// Whether the chargeback froze the account is recorded in it, so that the
+/// history replays the same way without `freeze_on_partial_chargeback`.
+fn apply_chargeback(
+    transaction: &mut Transaction,
+    account: &mut Account,
+    options: &ApplyOptions,
+) -> Result<(), TransactionError> {
+    let disputed = dispute_hold(transaction, account)?;
+    let charged_back = match transaction.amount == Currency::ZERO {
+        true => disputed,
+        false => transaction.amount,
+    };
+    if charged_back > disputed {
+        return Err(TransactionError::ChargebackExceedsDispute {
+            amount: charged_back,
+            disputed,
+        });
     }
 
-    let disputed_transaction = account
-        .transactions
-        .get(&transaction.transaction_id)
-        .context("disputed transaction not found")?;
-
-    assert!(account.held >= disputed_transaction.amount);
-    account.held -= disputed_transaction.amount;
-    account.total -= disputed_transaction.amount;
-    account.freeze();
+    account.held -= disputed;
+    account.total -= charged_back;
+    account.available += disputed - charged_back;
+    account.disputes.remove(&transaction.transaction_id);
+    transaction.froze_account |= charged_back == disputed || options.freeze_on_partial_chargeback;
+    if transaction.froze_account {
+        account.freeze();
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicUsize;
+
     use proptest::prelude::*;
 
     use super::*;
 
-    use crate::{account::ClientId, currency::Currency, transaction::TransactionId};
+    use crate::{account::ClientId, transaction::TransactionId};
 
     fn vec_transactions(count: usize) -> impl Strategy<Value = Vec<Transaction>> {
         prop::collection::vec(any::<Transaction>(), 1..count)
@@ -220,6 +1089,310 @@ mod tests {
         }
     }
 
+    /// Generates a (deposit, dispute, resolve, dispute) sequence for a single
+    /// transaction id.
+    fn redispute_sequence() -> impl Strategy<Value = Vec<Transaction>> {
+        (
+            any::<ClientId>(),
+            any::<TransactionId>(),
+            0.01..1_000_000.0f64,
+        )
+            .prop_map(|(client_id, transaction_id, amount)| {
+                [
+                    TransactionType::Deposit,
+                    TransactionType::Dispute,
+                    TransactionType::Resolve,
+                    TransactionType::Dispute,
+                ]
+                .into_iter()
+                .map(|transaction_type| {
+                    let amount = match transaction_type {
+                        TransactionType::Deposit => Currency::from_f64(amount),
+                        _ => Currency::default(),
+                    };
+                    Transaction::builder()
+                        .transaction_type(transaction_type)
+                        .client_id(client_id)
+                        .transaction_id(transaction_id)
+                        .amount(amount)
+                        .build()
+                        .unwrap()
+                })
+                .collect()
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_redispute_after_resolve_proptest(transactions in redispute_sequence()) {
+            let mut account = Account::builder().client_id(transactions[0].client_id).build();
+            let deposited = transactions[0].amount;
+            for transaction in transactions {
+                prop_assert!(apply_transaction(transaction, &mut account).is_ok());
+            }
+
+            // The second dispute should hold the funds again.
+            prop_assert_eq!(account.available, Currency::default());
+            prop_assert_eq!(account.held, deposited);
+            prop_assert_eq!(account.total, deposited);
+            account.sanity_check();
+        }
+    }
+
+    #[test]
+    fn test_backpressure_observer() {
+        let database = AccountDatabase::default();
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let observed = warnings.clone();
+        let config = ProcessorConfig::builder()
+            .channel_capacity(1)
+            .backpressure_threshold(Duration::from_millis(10))
+            .backpressure_observer(Arc::new(move |_| {
+                observed.fetch_add(1, Ordering::SeqCst);
+            }))
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+
+        // Stall the processing thread by holding the account lock for a while.
+        let account_mutex = database.account(ClientId::from(1));
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let stall = thread::spawn(move || {
+            let _account = account_mutex.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+        locked_rx.recv().unwrap();
+
+        for id in 1..=4 {
+            let transaction = Transaction::builder()
+                .transaction_type(TransactionType::Deposit)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(id))
+                .amount(Currency::from_f64(1.0))
+                .build()
+                .unwrap();
+            processor.send_transaction(transaction).unwrap();
+        }
+        stall.join().unwrap();
+        processor.close();
+
+        assert!(warnings.load(Ordering::SeqCst) > 0);
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_close_and_verify() {
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        for transaction in [
+            TransactionType::Deposit,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+        ]
+        .map(|transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(1))
+                .amount(Currency::from_f64(10.0))
+                .build()
+                .unwrap()
+        }) {
+            processor.send_transaction(transaction).unwrap();
+        }
+        processor.close_and_verify().unwrap();
+
+        // Corrupt an account directly.
+        database
+            .account(ClientId::from(2))
+            .lock()
+            .unwrap()
+            .available = Currency::from_f64(5.0);
+        let processor = Processor::new(database.clone());
+        processor
+            .close_and_verify()
+            .expect_err("corrupted account should fail verification");
+    }
+
+    fn deposit(client_id: u16, transaction_id: u32, amount: f64) -> Transaction {
+        Transaction::builder()
+            .transaction_type(TransactionType::Deposit)
+            .client_id(ClientId::from(client_id))
+            .transaction_id(TransactionId::from(transaction_id))
+            .amount(Currency::from_f64(amount))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_monotonic_tx_ids() {
+        // Accepted by default.
+        let database = AccountDatabase::default();
+        let processor = Processor::new(database.clone());
+        processor.send_transaction(deposit(1, 2, 10.0)).unwrap();
+        processor.send_transaction(deposit(1, 1, 5.0)).unwrap();
+        processor.close();
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(15.0)
+        );
+
+        // Rejected in monotonic mode.
+        let database = AccountDatabase::default();
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder()
+            .monotonic_tx_ids(true)
+            .dead_letters(dead_tx)
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        processor.send_transaction(deposit(1, 2, 10.0)).unwrap();
+        processor.send_transaction(deposit(1, 1, 5.0)).unwrap();
+        processor.close();
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(10.0)
+        );
+
+        let dead_letters: Vec<DeadLetter> = dead_rx.try_iter().collect();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].entry,
+            DeadLetterEntry::Transaction(deposit(1, 1, 5.0))
+        );
+        assert!(dead_letters[0]
+            .reason
+            .contains("not greater than previous id 2"));
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_monotonic_tx_ids_with_workers() {
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder()
+            .workers(4)
+            .monotonic_tx_ids(true)
+            .dead_letters(dead_tx)
+            .build();
+        let processor = Processor::with_config(AccountDatabase::default(), config);
+        let mut expected = Vec::new();
+        for i in 1..=100 {
+            processor
+                .send_transaction(deposit((i % 8) as u16, 2 * i, 1.0))
+                .unwrap();
+            if i % 10 == 0 {
+                // Lower than the id just sent, but for another client, and
+                // so usually another worker.
+                let late = deposit((i % 8 + 1) as u16, 2 * i - 1, 1.0);
+                expected.push(late.transaction_id);
+                processor.send_transaction(late).unwrap();
+            }
+        }
+        // Rejected when applied, but its id still counts.
+        let mut withdrawal = deposit(1, 300, 1000.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        processor.send_transaction(withdrawal).unwrap();
+        processor.send_transaction(deposit(2, 299, 1.0)).unwrap();
+        expected.extend([TransactionId::from(299), TransactionId::from(300)]);
+
+        let metrics = processor.close();
+        assert_eq!(metrics.received, 112);
+        assert_eq!(metrics.applied, 100);
+        let mut rejected: Vec<TransactionId> = dead_rx
+            .try_iter()
+            .map(|x| match x.entry {
+                DeadLetterEntry::Transaction(t) => t.transaction_id,
+                DeadLetterEntry::Row { .. } => unreachable!(),
+            })
+            .collect();
+        rejected.sort();
+        assert_eq!(rejected, expected);
+    }
+
+    #[test]
+    fn test_max_failures() {
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder().max_failures(2).build();
+        let processor = Processor::with_config(database.clone(), config);
+        processor.send_transaction(deposit(1, 1, 10.0)).unwrap();
+        // Duplicate id.
+        processor.send_transaction(deposit(1, 1, 1.0)).unwrap();
+        processor.sync().unwrap();
+        processor.send_transaction(deposit(1, 2, 5.0)).unwrap();
+        processor.send_transaction(deposit(1, 2, 1.0)).unwrap();
+        while processor.metrics().failed < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let err = processor
+            .send_transaction(deposit(1, 3, 1.0))
+            .expect_err("failure limit");
+        assert_eq!(
+            err.downcast_ref::<FailureLimitReached>(),
+            Some(&FailureLimitReached(2))
+        );
+        let metrics = processor.close();
+        assert_eq!(metrics.failed, 2);
+        assert_eq!(metrics.applied, 2);
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(
+            account_mutex.lock().unwrap().available,
+            Currency::from_f64(15.0)
+        );
+    }
+
+    #[test]
+    fn test_reserved_clients() {
+        let database = AccountDatabase::default();
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder()
+            .reserved_clients(HashSet::from([ClientId::from(0)]))
+            .dead_letters(dead_tx)
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        processor.send_transaction(deposit(0, 1, 10.0)).unwrap();
+        processor.send_transaction(deposit(1, 2, 5.0)).unwrap();
+        let metrics = processor.close();
+        assert_eq!(metrics.failed, 1);
+        assert_eq!(database.client_ids(), vec![ClientId::from(1)]);
+
+        let dead_letters: Vec<DeadLetter> = dead_rx.try_iter().collect();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].entry,
+            DeadLetterEntry::Transaction(deposit(0, 1, 10.0))
+        );
+        assert_eq!(dead_letters[0].reason, "client id 0 is reserved");
+    }
+
+    #[test]
+    fn test_poisoned_account_recovery() {
+        let database = AccountDatabase::default();
+        let account_mutex = database.account(ClientId::from(1));
+        let poisoner = account_mutex.clone();
+        thread::spawn(move || {
+            let _account = poisoner.lock().unwrap();
+            panic!("induced panic while holding the account lock");
+        })
+        .join()
+        .expect_err("thread should panic");
+        assert!(account_mutex.is_poisoned());
+
+        // Processing continues for the affected account.
+        let processor = Processor::new(database.clone());
+        processor.send_transaction(deposit(1, 1, 10.0)).unwrap();
+        let metrics = processor.close_and_verify().unwrap();
+        assert_eq!(metrics.applied, 1);
+
+        assert!(!account_mutex.is_poisoned());
+        assert_eq!(database.recovered_accounts(), vec![ClientId::from(1)]);
+        assert_eq!(
+            database.lock_account(&account_mutex).available,
+            Currency::from_f64(10.0)
+        );
+    }
+
     fn init_account(initial_balance: f64) -> Account {
         let mut account = Account::builder().client_id(ClientId::from(1)).build();
         let amount = Currency::from_f64(initial_balance);
@@ -228,7 +1401,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
             .amount(amount)
-            .build();
+            .build()
+            .unwrap();
         apply_transaction(transaction.clone(), &mut account).unwrap();
         account
     }
@@ -243,7 +1417,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
             .amount(amount)
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, amount);
@@ -270,7 +1445,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(2))
             .amount(Currency::from_f64(42.0))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, Currency::from_f64(100.0 - 42.0));
@@ -296,7 +1472,8 @@ mod tests {
             .transaction_type(TransactionType::Dispute)
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, Currency::from_f64(0.));
@@ -317,7 +1494,8 @@ mod tests {
             .transaction_type(TransactionType::Resolve)
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, Currency::from_f64(100.));
@@ -326,6 +1504,1138 @@ mod tests {
         account.sanity_check();
     }
 
+    #[test]
+    fn test_concurrent_processors_share_database() {
+        const CLIENTS: u16 = 200;
+        const ROUNDS: u32 = 50;
+
+        let database = AccountDatabase::default();
+        // Each processor handles every other client, creating accounts
+        // concurrently in the same map.
+        let handles: Vec<_> = (0..2u16)
+            .map(|offset| {
+                let database = database.clone();
+                thread::spawn(move || {
+                    let config = ProcessorConfig::builder().workers(2).build();
+                    let processor = Processor::with_config(database, config);
+                    for round in 0..ROUNDS {
+                        for client in (offset..CLIENTS).step_by(2) {
+                            let id = round * 2 * u32::from(CLIENTS) + u32::from(client) * 2;
+                            processor
+                                .send_transaction(deposit(client, id, 2.0))
+                                .unwrap();
+                            let mut withdrawal = deposit(client, id + 1, 1.0);
+                            withdrawal.transaction_type = TransactionType::Withdrawal;
+                            processor.send_transaction(withdrawal).unwrap();
+                        }
+                    }
+                    processor.close()
+                })
+            })
+            .collect();
+        for handle in handles {
+            let metrics = handle.join().unwrap();
+            assert_eq!(metrics.failed, 0);
+            assert_eq!(metrics.applied, u64::from(CLIENTS) * u64::from(ROUNDS));
+        }
+
+        database.verify_all_accounts();
+        let totals = database.totals().unwrap();
+        assert_eq!(totals.accounts, usize::from(CLIENTS));
+        assert_eq!(
+            totals.total,
+            Currency::from_f64(f64::from(CLIENTS) * f64::from(ROUNDS))
+        );
+    }
+
+    #[test]
+    fn test_abort() {
+        // Hold the worker inside the first transaction until released.
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let validator: Validator = Arc::new(move |_, _| {
+            entered_tx.send(()).ok();
+            release_rx.lock().unwrap().recv().ok();
+            Ok(())
+        });
+
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder()
+            .apply_options(ApplyOptions::builder().validator(validator).build())
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        for tx in 1..=5 {
+            processor.send_transaction(deposit(1, tx, 1.0)).unwrap();
+        }
+        entered_rx.recv().unwrap();
+
+        processor.abort();
+        drop(release_tx);
+        processor
+            .send_transaction(deposit(1, 6, 1.0))
+            .expect_err("send after abort should fail");
+
+        // Only the transaction in progress was applied.
+        let metrics = processor.close();
+        assert_eq!(metrics.applied, 1);
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(account_mutex.lock().unwrap().total, Currency::from_f64(1.0));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        // Hold the worker inside the first transaction until released.
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let validator: Validator = Arc::new(move |_, _| {
+            entered_tx.send(()).ok();
+            release_rx.lock().unwrap().recv().ok();
+            Ok(())
+        });
+
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder()
+            .workers(2)
+            .max_in_flight(2)
+            .apply_options(ApplyOptions::builder().validator(validator).build())
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        // One per worker, so that neither channel is full.
+        processor.send_transaction(deposit(1, 1, 1.0)).unwrap();
+        processor.send_transaction(deposit(2, 2, 1.0)).unwrap();
+        entered_rx.recv().unwrap();
+
+        let (sent_tx, sent_rx) = std::sync::mpsc::channel();
+        thread::scope(|s| {
+            s.spawn(|| {
+                processor.send_transaction(deposit(1, 3, 1.0)).unwrap();
+                sent_tx.send(()).unwrap();
+            });
+            // Blocked at the cap until a transaction completes.
+            assert!(sent_rx.recv_timeout(Duration::from_millis(50)).is_err());
+            release_tx.send(()).unwrap();
+            sent_rx.recv().unwrap();
+        });
+
+        // Blocked again, until aborted.
+        thread::scope(|s| {
+            let blocked = s.spawn(|| processor.send_transaction(deposit(1, 4, 1.0)));
+            thread::sleep(Duration::from_millis(20));
+            processor.abort();
+            blocked
+                .join()
+                .unwrap()
+                .expect_err("send after abort should fail");
+        });
+        drop(release_tx);
+        processor.close();
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let at = |minute: u32| {
+            DateTime::parse_from_rfc3339(&format!("2024-06-01T12:{minute:02}:00Z"))
+                .unwrap()
+                .to_utc()
+        };
+        let mut deposit_1 = deposit(1, 1, 10.0);
+        deposit_1.timestamp = Some(at(0));
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(1))
+            .timestamp(at(1))
+            .build()
+            .unwrap();
+        let mut deposit_2 = deposit(1, 2, 5.0);
+        deposit_2.timestamp = Some(at(2));
+
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder().reorder_window(2).build();
+        let processor = Processor::with_config(database.clone(), config);
+        // The dispute arrives before the deposit it refers to.
+        for transaction in [dispute, deposit_1, deposit_2] {
+            processor.send_transaction(transaction).unwrap();
+        }
+        let metrics = processor.close();
+        assert_eq!(metrics.failed, 0);
+
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.held, Currency::from_f64(10.0));
+        assert_eq!(account.available, Currency::from_f64(5.0));
+        let order: Vec<TransactionType> =
+            account.history.iter().map(|x| x.transaction_type).collect();
+        assert_eq!(
+            order,
+            vec![
+                TransactionType::Deposit,
+                TransactionType::Dispute,
+                TransactionType::Deposit
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reorder_equal_timestamps() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let untimestamped = deposit(1, 1, 1.0);
+        let mut deposit_3 = deposit(1, 3, 3.0);
+        deposit_3.timestamp = Some(timestamp);
+        let mut deposit_2 = deposit(1, 2, 2.0);
+        deposit_2.timestamp = Some(timestamp);
+
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder().reorder_window(3).build();
+        let processor = Processor::with_config(database.clone(), config);
+        for transaction in [untimestamped, deposit_3, deposit_2] {
+            processor.send_transaction(transaction).unwrap();
+        }
+        processor.close();
+
+        let account_mutex = database.account(ClientId::from(1));
+        let account = account_mutex.lock().unwrap();
+        let order: Vec<TransactionId> = account.history.iter().map(|x| x.transaction_id).collect();
+        assert_eq!(
+            order,
+            vec![
+                TransactionId::from(2),
+                TransactionId::from(3),
+                TransactionId::from(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validator() {
+        // Reject deposits of an odd number of units.
+        let validator: Validator = Arc::new(|transaction, _account| {
+            let units: i64 = transaction
+                .amount
+                .to_string_with_precision(0)
+                .parse()
+                .unwrap();
+            match transaction.transaction_type == TransactionType::Deposit && units % 2 != 0 {
+                true => Err(TransactionError::Rejected("odd amount".to_string())),
+                false => Ok(()),
+            }
+        });
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder()
+            .apply_options(ApplyOptions::builder().validator(validator).build())
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        processor.send_transaction(deposit(1, 1, 2.0)).unwrap();
+        processor.send_transaction(deposit(1, 2, 3.0)).unwrap();
+        let metrics = processor.close();
+
+        assert_eq!(metrics.applied, 1);
+        assert_eq!(metrics.failed, 1);
+        let account_mutex = database.account(ClientId::from(1));
+        assert_eq!(account_mutex.lock().unwrap().total, Currency::from_f64(2.0));
+    }
+
+    #[test]
+    fn test_validator_skipped_for_stateless_rejections() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let validator: Validator = Arc::new(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        });
+        let options = ApplyOptions::builder()
+            .validator(validator)
+            .max_precision(2)
+            .build();
+
+        let mut account = init_account(10.0);
+        let mut too_precise = deposit(1, 2, 0.0);
+        too_precise.amount = "1.001".parse().unwrap();
+        apply_transaction_with(too_precise, &mut account, &options).unwrap_err();
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        apply_transaction_with(deposit(1, 3, 1.0), &mut account, &options).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_chargeback_after_resolve() {
+        let mut account = init_account(100.0);
+        for transaction_type in [TransactionType::Dispute, TransactionType::Resolve] {
+            let transaction = Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(1))
+                .build()
+                .unwrap();
+            apply_transaction(transaction, &mut account).unwrap();
+        }
+
+        let chargeback = Transaction::builder()
+            .transaction_type(TransactionType::Chargeback)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(1))
+            .build()
+            .unwrap();
+        let err = apply_transaction(chargeback, &mut account)
+            .expect_err("chargeback after resolve should be rejected");
+        assert_eq!(err.to_string(), "transaction not in dispute");
+        assert!(!account.is_locked());
+        assert_eq!(account.available, Currency::from_f64(100.0));
+        assert_eq!(account.total, Currency::from_f64(100.0));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_redispute_then_chargeback() {
+        let mut account = init_account(100.0);
+        let event = |transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(1))
+                .build()
+                .unwrap()
+        };
+
+        apply_transaction(event(TransactionType::Dispute), &mut account).unwrap();
+        apply_transaction(event(TransactionType::Resolve), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(100.0));
+        assert_eq!(account.held, Currency::ZERO);
+
+        // A second claim against the same deposit holds the funds again.
+        apply_transaction(event(TransactionType::Dispute), &mut account).unwrap();
+        assert_eq!(account.available, Currency::ZERO);
+        assert_eq!(account.held, Currency::from_f64(100.0));
+        assert_eq!(account.total, Currency::from_f64(100.0));
+
+        apply_transaction(event(TransactionType::Chargeback), &mut account).unwrap();
+        assert_eq!(account.available, Currency::ZERO);
+        assert_eq!(account.held, Currency::ZERO);
+        assert_eq!(account.total, Currency::ZERO);
+        assert!(account.is_locked());
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_balances_only() {
+        let options = ApplyOptions::builder().balances_only(true).build();
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(1))
+            .build()
+            .unwrap();
+        for transaction in [deposit(1, 1, 10.0), deposit(1, 2, 5.0), dispute] {
+            apply_transaction_with(transaction, &mut account, &options).unwrap();
+        }
+        assert!(account.history.is_empty());
+        assert_eq!(account.held, Currency::from_f64(10.0));
+        assert_eq!(account.available, Currency::from_f64(5.0));
+
+        // Duplicates are still detected from the cache.
+        let err = apply_transaction_with(deposit(1, 2, 5.0), &mut account, &options)
+            .expect_err("duplicate");
+        assert_eq!(
+            err,
+            TransactionError::DuplicateTransaction {
+                original: TransactionType::Deposit
+            }
+        );
+    }
+
+    #[test]
+    fn test_duplicate_id_reports_original_type() {
+        let mut account = init_account(100.0);
+        let mut withdrawal = deposit(1, 1, 10.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        let err = apply_transaction(withdrawal, &mut account).expect_err("duplicate id");
+        assert_eq!(
+            err.to_string(),
+            "transaction id already exists (originally a deposit)"
+        );
+        assert_eq!(account.available, Currency::from_f64(100.0));
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit() {
+        let start = DateTime::parse_from_rfc3339("2024-06-01T23:00:00Z")
+            .unwrap()
+            .to_utc();
+        let clock = Arc::new(crate::clock::MockClock::new(start));
+        let database = AccountDatabase::default().with_clock(clock.clone());
+        let options = ApplyOptions::builder()
+            .daily_withdrawal_limit(Currency::from_f64(100.0))
+            .build();
+        database.apply(deposit(1, 1, 500.0)).unwrap();
+        let withdrawal = |id, amount| {
+            let mut withdrawal = deposit(1, id, amount);
+            withdrawal.transaction_type = TransactionType::Withdrawal;
+            withdrawal
+        };
+
+        database.apply_with(withdrawal(2, 60.0), &options).unwrap();
+        assert_eq!(
+            database.apply_with(withdrawal(3, 50.0), &options),
+            Err(TransactionError::DailyLimitExceeded {
+                limit: Currency::from_f64(100.0)
+            })
+        );
+        database.apply_with(withdrawal(3, 40.0), &options).unwrap();
+
+        // A new day (in UTC) starts with a fresh limit.
+        clock.advance(chrono::TimeDelta::hours(1));
+        database.apply_with(withdrawal(4, 100.0), &options).unwrap();
+
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        let account = account_mutex.lock().unwrap();
+        assert_eq!(account.available, Currency::from_f64(300.0));
+        assert_eq!(account.history[1].timestamp, Some(start));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit_direct() {
+        let start = DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let options = ApplyOptions::builder()
+            .daily_withdrawal_limit(Currency::from_f64(100.0))
+            .clock(Arc::new(crate::clock::MockClock::new(start)))
+            .build();
+        let mut account = init_account(500.0);
+        let mut withdrawal = deposit(1, 2, 80.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        apply_transaction_with(withdrawal, &mut account, &options).unwrap();
+        assert_eq!(account.history[1].timestamp, Some(start));
+
+        // A reversed withdrawal doesn't count towards the limit.
+        let reversal = Transaction::builder()
+            .transaction_type(TransactionType::Reversal)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .build()
+            .unwrap();
+        apply_transaction_with(reversal, &mut account, &options).unwrap();
+        let mut withdrawal = deposit(1, 3, 90.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        apply_transaction_with(withdrawal, &mut account, &options).unwrap();
+        assert_eq!(account.available, Currency::from_f64(410.0));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_stateless_checks_before_lock() {
+        let options = ApplyOptions::builder().max_precision(2).build();
+        let too_precise = |client_id, transaction_id| {
+            let mut transaction = deposit(client_id, transaction_id, 0.0);
+            transaction.amount = "1.001".parse().unwrap();
+            transaction
+        };
+        let transactions = [
+            deposit(1, 1, 10.0),
+            too_precise(1, 2),
+            // Unknown client.
+            too_precise(2, 3),
+            deposit(1, 4, 2.5),
+        ];
+
+        // The same transactions applied directly to an account, which goes
+        // through the checks after locking.
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        let expected: Vec<_> = transactions
+            .iter()
+            .filter_map(|t| apply_transaction_with(t.clone(), &mut account, &options).err())
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(expected.len(), 2);
+
+        let database = AccountDatabase::default();
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder()
+            .apply_options(options)
+            .dead_letters(dead_tx)
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        for transaction in transactions {
+            processor.send_transaction(transaction).unwrap();
+        }
+        assert_eq!(processor.close().failed, 2);
+
+        let reasons: Vec<String> = dead_rx.try_iter().map(|x| x.reason).collect();
+        assert_eq!(reasons, expected);
+        // Rejected before the account was looked up, so none was created.
+        assert_eq!(database.client_ids(), vec![ClientId::from(1)]);
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(account_mutex.lock().unwrap().summary(), account.summary());
+    }
+
+    #[test]
+    fn test_max_precision() {
+        let options = ApplyOptions::builder().max_precision(2).build();
+        let mut account = init_account(100.0);
+        let mut transaction = deposit(1, 2, 0.0);
+        transaction.amount = "1.001".parse().unwrap();
+        let err = apply_transaction_with(transaction.clone(), &mut account, &options)
+            .expect_err("too many decimal places");
+        assert_eq!(err.to_string(), "amount has more than 2 decimal places");
+
+        transaction.amount = "1.010".parse().unwrap();
+        apply_transaction_with(transaction, &mut account, &options).unwrap();
+        assert_eq!(account.total, "101.01".parse().unwrap());
+    }
+
+    #[test]
+    fn test_type_precision() {
+        let options = ApplyOptions::builder()
+            .type_precision(HashMap::from([(TransactionType::Withdrawal, 2)]))
+            .build();
+        let mut account = init_account(100.0);
+        let mut transaction = deposit(1, 2, 0.0);
+        transaction.amount = "1.001".parse().unwrap();
+        apply_transaction_with(transaction.clone(), &mut account, &options).unwrap();
+
+        transaction.transaction_type = TransactionType::Withdrawal;
+        transaction.transaction_id = TransactionId::from(3);
+        assert_eq!(
+            apply_transaction_with(transaction.clone(), &mut account, &options),
+            Err(TransactionError::TooPrecise { max: 2 })
+        );
+        transaction.amount = "1.01".parse().unwrap();
+        apply_transaction_with(transaction, &mut account, &options).unwrap();
+        assert_eq!(account.total, "99.991".parse().unwrap());
+    }
+
+    #[test]
+    fn test_zero_amount_rejected() {
+        let mut account = init_account(100.0);
+        let err = apply_transaction(deposit(1, 2, 0.0), &mut account)
+            .expect_err("zero deposit should be rejected");
+        assert_eq!(err.to_string(), "amount must be positive");
+        // The id is still free.
+        apply_transaction(deposit(1, 2, 1.0), &mut account).unwrap();
+
+        let mut withdrawal = deposit(1, 3, 0.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        assert_eq!(
+            apply_transaction(withdrawal, &mut account),
+            Err(TransactionError::ZeroAmount)
+        );
+
+        // Disputes carry no amount.
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .build()
+            .unwrap();
+        apply_transaction(dispute, &mut account).unwrap();
+        assert_eq!(account.total, Currency::from_f64(101.0));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_adjustment() {
+        let mut account = init_account(100.0);
+        let adjustment = |id, amount| {
+            Transaction::builder()
+                .transaction_type(TransactionType::Adjustment)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(id))
+                .amount(Currency::from_f64(amount))
+                .reference("manual correction")
+                .build()
+                .unwrap()
+        };
+
+        apply_transaction(adjustment(2, 25.0), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(125.0));
+        assert_eq!(account.total, Currency::from_f64(125.0));
+
+        apply_transaction(adjustment(3, -40.5), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(84.5));
+        assert_eq!(account.total, Currency::from_f64(84.5));
+        assert_eq!(account.history.len(), 3);
+        account.sanity_check();
+
+        assert_eq!(
+            apply_transaction(adjustment(4, -100.0), &mut account),
+            Err(TransactionError::InsufficientFunds)
+        );
+        let mut unexplained = adjustment(4, 1.0);
+        unexplained.reference = None;
+        assert_eq!(
+            apply_transaction(unexplained, &mut account),
+            Err(TransactionError::MissingReference)
+        );
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(3))
+            .build()
+            .unwrap();
+        assert_eq!(
+            apply_transaction(dispute, &mut account),
+            Err(TransactionError::AdjustmentDisputed)
+        );
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_reversal() {
+        let mut account = init_account(100.0);
+        let mut withdrawal = deposit(1, 2, 30.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        apply_transaction(withdrawal, &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(70.0));
+
+        let reversal = |id| {
+            Transaction::builder()
+                .transaction_type(TransactionType::Reversal)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(id))
+                .build()
+                .unwrap()
+        };
+        // Only the most recent transaction can be reversed.
+        assert_eq!(
+            apply_transaction(reversal(1), &mut account),
+            Err(TransactionError::NotMostRecent)
+        );
+
+        apply_transaction(reversal(2), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(100.0));
+        assert_eq!(account.total, Currency::from_f64(100.0));
+        assert_eq!(
+            account.history.last().unwrap().transaction_type,
+            TransactionType::Reversal
+        );
+        // Not twice, and the id can't be reused.
+        assert_eq!(
+            apply_transaction(reversal(2), &mut account),
+            Err(TransactionError::NotMostRecent)
+        );
+        assert!(apply_transaction(deposit(1, 2, 1.0), &mut account).is_err());
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_reversal_of_disputed_rejected() {
+        let mut account = init_account(100.0);
+        let event = |transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(1))
+                .build()
+                .unwrap()
+        };
+        apply_transaction(event(TransactionType::Dispute), &mut account).unwrap();
+        assert_eq!(
+            apply_transaction(event(TransactionType::Reversal), &mut account),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        assert_eq!(account.held, Currency::from_f64(100.0));
+
+        // A reversed deposit can't be disputed.
+        let mut account = init_account(100.0);
+        apply_transaction(event(TransactionType::Reversal), &mut account).unwrap();
+        assert_eq!(account.total, Currency::ZERO);
+        assert_eq!(
+            apply_transaction(event(TransactionType::Dispute), &mut account),
+            Err(TransactionError::Reversed)
+        );
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_reversal_with_compaction() {
+        let options = ApplyOptions::builder().max_history(1).build();
+        let mut account = init_account(100.0);
+        apply_transaction_with(deposit(1, 2, 50.0), &mut account, &options).unwrap();
+        let event = |transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(2))
+                .build()
+                .unwrap()
+        };
+        // The deposit has been compacted away.
+        apply_transaction_with(event(TransactionType::Reversal), &mut account, &options).unwrap();
+        assert_eq!(account.history.len(), 1);
+        assert_eq!(account.total, Currency::from_f64(100.0));
+        account.sanity_check();
+
+        // So has the reversal.
+        apply_transaction_with(deposit(1, 3, 10.0), &mut account, &options).unwrap();
+        assert!(
+            apply_transaction_with(event(TransactionType::Dispute), &mut account, &options)
+                .is_err()
+        );
+        assert_eq!(account.held, Currency::ZERO);
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_reversal_balances_only() {
+        let options = ApplyOptions::builder().balances_only(true).build();
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        let mut withdrawal = deposit(1, 2, 30.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        apply_transaction_with(deposit(1, 1, 100.0), &mut account, &options).unwrap();
+        apply_transaction_with(withdrawal, &mut account, &options).unwrap();
+
+        let reversal = Transaction::builder()
+            .transaction_type(TransactionType::Reversal)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .build()
+            .unwrap();
+        apply_transaction_with(reversal.clone(), &mut account, &options).unwrap();
+        assert_eq!(account.available, Currency::from_f64(100.0));
+        assert_eq!(
+            apply_transaction_with(reversal, &mut account, &options),
+            Err(TransactionError::NotMostRecent)
+        );
+    }
+
+    #[test]
+    fn test_reversal_keeps_withdrawal_fee() {
+        let fee_account = ClientId::from(99);
+        let database = AccountDatabase::default().with_fee_account(fee_account);
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("1.0".parse().unwrap())
+            .build();
+        let mut withdrawal = deposit(1, 2, 2.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        for transaction in [deposit(1, 1, 10.0), withdrawal] {
+            database.apply_with(transaction, &options).unwrap();
+        }
+
+        // The fee has changed since the withdrawal, and the reversal's own
+        // amount is replaced by the amount refunded.
+        let options = ApplyOptions::builder()
+            .withdrawal_fee("3.0".parse().unwrap())
+            .build();
+        let reversal = Transaction::builder()
+            .transaction_type(TransactionType::Reversal)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .amount(Currency::from_f64(0.5))
+            .build()
+            .unwrap();
+        database.apply_with(reversal, &options).unwrap();
+
+        // The withdrawal is refunded, but the fee it was charged is not.
+        let client = database.account(ClientId::from(1));
+        {
+            let client = client.lock().unwrap();
+            assert_eq!(client.available, Currency::from_f64(9.0));
+            assert_eq!(client.history[1].fee(), Currency::from_f64(1.0));
+            assert_eq!(client.history[2].amount(), Currency::from_f64(2.0));
+            let restored = Account::from_json(&client.to_json()).unwrap();
+            assert_eq!(&restored, &*client);
+        }
+        let fees = database.account(fee_account);
+        assert_eq!(fees.lock().unwrap().available, Currency::from_f64(1.0));
+        assert_eq!(database.totals().unwrap().total, Currency::from_f64(10.0));
+        database.conservation_check().unwrap();
+        database.verify_all_accounts();
+    }
+
+    #[test]
+    fn test_dispute_unsettled() {
+        let mut account = init_account(100.0);
+        let mut unsettled = deposit(1, 2, 50.0);
+        unsettled.settled = false;
+        apply_transaction(unsettled, &mut account).unwrap();
+
+        let dispute = |id| {
+            Transaction::builder()
+                .transaction_type(TransactionType::Dispute)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(id))
+                .build()
+                .unwrap()
+        };
+        let err = apply_transaction(dispute(2), &mut account)
+            .expect_err("unsettled deposit should not be disputable");
+        assert_eq!(err, TransactionError::Unsettled);
+        assert_eq!(account.held, Currency::ZERO);
+
+        // The initial deposit is settled by default.
+        apply_transaction(dispute(1), &mut account).unwrap();
+        assert_eq!(account.held, Currency::from_f64(100.0));
+        assert_eq!(account.available, Currency::from_f64(50.0));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_idempotent_disputes() {
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(1))
+            .build()
+            .unwrap();
+
+        // Strict by default.
+        let mut account = init_account(100.0);
+        apply_transaction(dispute.clone(), &mut account).unwrap();
+        apply_transaction(dispute.clone(), &mut account)
+            .expect_err("duplicate dispute should be rejected");
+
+        // No-op when idempotent.
+        let options = ApplyOptions::builder().idempotent_disputes(true).build();
+        let mut account = init_account(100.0);
+        apply_transaction_with(dispute.clone(), &mut account, &options).unwrap();
+        let before = account.clone();
+        apply_transaction_with(dispute, &mut account, &options).unwrap();
+        assert_eq!(account, before);
+        assert_eq!(account.available, Currency::from_f64(0.));
+        assert_eq!(account.held, Currency::from_f64(100.));
+        account.sanity_check();
+    }
+
+    #[test]
+    fn test_compact_history() {
+        let options = ApplyOptions::builder().max_history(2).build();
+        let mut account = Account::builder().client_id(ClientId::from(1)).build();
+        let dispute = |tx, transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(tx))
+                .build()
+                .unwrap()
+        };
+        for transaction in [
+            deposit(1, 1, 10.0),
+            deposit(1, 2, 20.0),
+            dispute(1, TransactionType::Dispute),
+            deposit(1, 3, 30.0),
+            deposit(1, 4, 40.0),
+            deposit(1, 5, 50.0),
+        ] {
+            apply_transaction_with(transaction, &mut account, &options).unwrap();
+            account.sanity_check();
+        }
+
+        assert_eq!(account.history.len(), 2);
+        let summary = account.compacted.as_ref().unwrap();
+        assert_eq!(summary.count, 4);
+        assert_eq!(summary.held, Currency::from_f64(10.0));
+        assert_eq!(summary.total, Currency::from_f64(60.0));
+        // Only the disputed deposit is kept from the compacted history.
+        assert!(account.transactions.contains_key(&TransactionId::from(1)));
+        assert!(!account.transactions.contains_key(&TransactionId::from(2)));
+
+        apply_transaction_with(dispute(1, TransactionType::Resolve), &mut account, &options)
+            .unwrap();
+        account.sanity_check();
+        assert_eq!(account.available, Currency::from_f64(150.0));
+        assert_eq!(account.held, Currency::default());
+
+        let err =
+            apply_transaction_with(dispute(2, TransactionType::Dispute), &mut account, &options)
+                .expect_err("compacted transactions cannot be disputed");
+        assert!(matches!(err, TransactionError::TransactionNotFound { .. }));
+    }
+
+    #[test]
+    fn test_sequence_gap() {
+        let processor = Processor::new(AccountDatabase::default());
+        for (tx, sequence) in [(1, 1), (2, 2), (3, 4), (4, 5)] {
+            let mut transaction = deposit(1, tx, 1.0);
+            transaction.sequence = Some(sequence);
+            processor.send_transaction(transaction).unwrap();
+        }
+        // Transactions without a sequence number are not checked.
+        processor.send_transaction(deposit(1, 5, 1.0)).unwrap();
+
+        let metrics = processor.close();
+        assert_eq!(metrics.sequence_gaps, 1);
+        assert_eq!(metrics.applied, 5);
+    }
+
+    #[test]
+    fn test_sweep_dust() {
+        let withdrawal = Transaction::builder()
+            .transaction_type(TransactionType::Withdrawal)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .amount(Currency::from_f64(1.0))
+            .build()
+            .unwrap();
+
+        // Dust is left behind by default.
+        let mut account = init_account(1.00005);
+        apply_transaction(withdrawal.clone(), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(0.00005));
+
+        let options = ApplyOptions::builder()
+            .dust_threshold(Currency::from_f64(0.0001))
+            .build();
+        let mut account = init_account(1.00005);
+        apply_transaction_with(withdrawal.clone(), &mut account, &options).unwrap();
+        assert_eq!(account.available, Currency::default());
+        assert_eq!(account.total, Currency::default());
+        assert_eq!(
+            account.history.last().unwrap().amount,
+            Currency::from_f64(1.00005)
+        );
+        account.sanity_check();
+
+        // Balances above the threshold are left alone.
+        let mut account = init_account(1.5);
+        apply_transaction_with(withdrawal, &mut account, &options).unwrap();
+        assert_eq!(account.available, Currency::from_f64(0.5));
+    }
+
+    #[test]
+    fn test_same_client_order() {
+        let (snapshot_tx, snapshot_rx) = std::sync::mpsc::channel();
+        let database = AccountDatabase::default();
+        let config = ProcessorConfig::builder()
+            .workers(4)
+            .channel_capacity(8)
+            .balance_snapshots(snapshot_tx)
+            .build();
+        let processor = Processor::with_config(database.clone(), config);
+        let mut tx = 0;
+        for amount in 1..=500 {
+            tx += 1;
+            processor
+                .send_transaction(deposit(1, tx, amount as f64))
+                .unwrap();
+            // Other clients on other workers, interleaved.
+            for client in 2..5 {
+                tx += 1;
+                processor
+                    .send_transaction(deposit(client, tx, 1.0))
+                    .unwrap();
+            }
+        }
+        processor.close_and_verify().unwrap();
+
+        // Every intermediate balance of client 1 is the running total in
+        // submission order.
+        let balances: Vec<Currency> = snapshot_rx
+            .try_iter()
+            .filter(|summary| summary.client_id == ClientId::from(1))
+            .map(|summary| summary.available)
+            .collect();
+        let expected: Vec<Currency> = (1..=500)
+            .scan(0, |sum, amount| {
+                *sum += amount;
+                Some(Currency::from_f64(*sum as f64))
+            })
+            .collect();
+        assert_eq!(balances, expected);
+
+        let account_mutex = database.get_account(ClientId::from(1)).unwrap();
+        let amounts: Vec<Currency> = account_mutex
+            .lock()
+            .unwrap()
+            .history
+            .iter()
+            .map(|x| x.amount)
+            .collect();
+        let sent: Vec<Currency> = (1..=500).map(|x| Currency::from_f64(x as f64)).collect();
+        assert_eq!(amounts, sent);
+    }
+
+    #[test]
+    fn test_balance_snapshots() {
+        let (snapshot_tx, snapshot_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder()
+            .balance_snapshots(snapshot_tx)
+            .build();
+        let processor = Processor::with_config(AccountDatabase::default(), config);
+        for transaction in [deposit(1, 1, 10.0), deposit(2, 2, 1.0), deposit(1, 3, 5.0)] {
+            processor.send_transaction(transaction).unwrap();
+        }
+        // Rejected, so no snapshot.
+        processor.send_transaction(deposit(1, 1, 1.0)).unwrap();
+        processor.close();
+
+        let snapshots: Vec<_> = snapshot_rx
+            .try_iter()
+            .map(|summary| (summary.client_id, summary.available, summary.total))
+            .collect();
+        let expected = |client, amount| {
+            let amount = Currency::from_f64(amount);
+            (ClientId::from(client), amount, amount)
+        };
+        assert_eq!(
+            snapshots,
+            vec![expected(1, 10.0), expected(2, 1.0), expected(1, 15.0)]
+        );
+    }
+
+    #[test]
+    fn test_event_log() {
+        let (log_tx, log_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder().event_log(log_tx).build();
+        let processor = Processor::with_config(AccountDatabase::default(), config);
+        let mut withdrawal = deposit(1, 3, 100.0);
+        withdrawal.transaction_type = TransactionType::Withdrawal;
+        let dispute = Transaction::builder()
+            .transaction_type(TransactionType::Dispute)
+            .client_id(ClientId::from(1))
+            .transaction_id(TransactionId::from(2))
+            .build()
+            .unwrap();
+        let transactions = [
+            deposit(1, 1, 10.0),
+            deposit(1, 2, 5.0),
+            // Rejected: insufficient funds.
+            withdrawal,
+            dispute,
+            // Rejected: duplicate id.
+            deposit(1, 1, 1.0),
+        ];
+        for transaction in transactions.clone() {
+            processor.send_transaction(transaction).unwrap();
+        }
+        let metrics = processor.close();
+
+        let logged: Vec<Transaction> = log_rx.try_iter().collect();
+        assert_eq!(logged.len() as u64, metrics.applied);
+        assert_eq!(
+            logged,
+            vec![
+                transactions[0].clone(),
+                transactions[1].clone(),
+                transactions[3].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dispute_wrong_client() {
+        let database = AccountDatabase::default();
+        let (dead_tx, dead_rx) = std::sync::mpsc::channel();
+        let config = ProcessorConfig::builder().dead_letters(dead_tx).build();
+        let processor = Processor::with_config(database.clone(), config);
+        processor.send_transaction(deposit(1, 1, 10.0)).unwrap();
+        processor
+            .send_transaction(
+                Transaction::builder()
+                    .transaction_type(TransactionType::Dispute)
+                    .client_id(ClientId::from(2))
+                    .transaction_id(TransactionId::from(1))
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        processor.close();
+
+        let dead_letters: Vec<DeadLetter> = dead_rx.try_iter().collect();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].reason,
+            "disputed transaction not found: tx 1 is not in the history of client 2 \
+             (it may belong to a different client)"
+        );
+    }
+
+    #[test]
+    fn test_partial_chargeback() {
+        let chargeback = |amount| {
+            Transaction::builder()
+                .transaction_type(TransactionType::Chargeback)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(2))
+                .amount(Currency::from_f64(amount))
+                .build()
+                .unwrap()
+        };
+        let disputed_account = || {
+            let mut account = init_account(60.0);
+            apply_transaction(deposit(1, 2, 30.0), &mut account).unwrap();
+            let dispute = Transaction::builder()
+                .transaction_type(TransactionType::Dispute)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(2))
+                .build()
+                .unwrap();
+            apply_transaction(dispute, &mut account).unwrap();
+            assert_eq!(account.held, Currency::from_f64(30.0));
+            account
+        };
+
+        // 20 of the 30 held is charged back, and the other 10 released.
+        let mut account = disputed_account();
+        assert_eq!(
+            apply_transaction(chargeback(40.0), &mut account),
+            Err(TransactionError::ChargebackExceedsDispute {
+                amount: Currency::from_f64(40.0),
+                disputed: Currency::from_f64(30.0),
+            })
+        );
+        apply_transaction(chargeback(20.0), &mut account).unwrap();
+        assert_eq!(account.available, Currency::from_f64(70.0));
+        assert_eq!(account.held, Currency::ZERO);
+        assert_eq!(account.total, Currency::from_f64(70.0));
+        assert!(!account.is_locked());
+        account.sanity_check();
+        apply_transaction(deposit(1, 3, 1.0), &mut account).unwrap();
+        account.sanity_check();
+
+        let options = ApplyOptions::builder()
+            .freeze_on_partial_chargeback(true)
+            .build();
+        let mut account = disputed_account();
+        apply_transaction_with(chargeback(20.0), &mut account, &options).unwrap();
+        assert_eq!(account.total, Currency::from_f64(70.0));
+        assert!(account.is_locked());
+        assert!(account.history.last().unwrap().froze_account());
+        account.sanity_check();
+        let mut rebuilt = account.clone();
+        rebuilt.rebuild_cache();
+        assert_eq!(rebuilt, account);
+
+        let database = AccountDatabase::default();
+        database.restore(vec![account]);
+        database.conservation_check().unwrap();
+    }
+
+    #[test]
+    fn test_held_below_dispute() {
+        let mut account = init_account(60.0);
+        apply_transaction(deposit(1, 2, 30.0), &mut account).unwrap();
+        let dispute_step = |transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(2))
+                .build()
+                .unwrap()
+        };
+        apply_transaction(dispute_step(TransactionType::Dispute), &mut account).unwrap();
+        assert_eq!(
+            account.disputes[&TransactionId::from(2)],
+            Currency::from_f64(30.0)
+        );
+
+        // An inconsistent account is reported, not a panic.
+        account.held = Currency::from_f64(10.0);
+        let expected = TransactionError::HeldBelowDispute {
+            held: Currency::from_f64(10.0),
+            disputed: Currency::from_f64(30.0),
+        };
+        for transaction_type in [TransactionType::Resolve, TransactionType::Chargeback] {
+            let err = apply_transaction(dispute_step(transaction_type), &mut account);
+            assert_eq!(err, Err(expected.clone()));
+        }
+    }
+
     #[test]
     fn test_dispute_chargeback() {
         let mut account = init_account(100.0);
@@ -334,7 +2644,8 @@ mod tests {
             .transaction_type(TransactionType::Dispute)
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, Currency::from_f64(0.));
@@ -355,7 +2666,8 @@ mod tests {
             .transaction_type(TransactionType::Chargeback)
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(1))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account).unwrap();
         assert_eq!(account.available, Currency::from_f64(0.));
@@ -370,13 +2682,15 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(3))
             .amount(Currency::from_f64(1.0))
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction.clone(), &mut account)
             .expect_err("transactions should be rejected if account is locked");
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_all() {
         let mut account = init_account(100.0);
         let amount0 = Currency::from_f64(100.0);
@@ -388,7 +2702,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(2))
             .amount(amount1)
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction1.clone(), &mut account).unwrap();
 
@@ -399,7 +2714,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(3))
             .amount(amount2)
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction2.clone(), &mut account).unwrap();
 
@@ -410,7 +2726,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(4))
             .amount(amount3)
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction3.clone(), &mut account).unwrap();
 
@@ -433,7 +2750,8 @@ mod tests {
             .client_id(ClientId::from(1))
             .transaction_id(TransactionId::from(5))
             .amount(amount4)
-            .build();
+            .build()
+            .unwrap();
 
         apply_transaction(transaction4.clone(), &mut account).unwrap();
 
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::account::{Account, ClientId};
+
+/// Storage backend for `AccountDatabase`.
+///
+/// Each account is handed out as an `Arc<Mutex<Account>>` so that different
+/// accounts can be operated on concurrently. Implementations only need to
+/// make the map of accounts itself thread-safe.
+pub trait AccountStore: Send + Sync + 'static {
+    /// Get the account for `client_id`, creating an empty one if needed.
+    ///
+    /// Concurrent calls for the same new client must return the same account.
+    fn account(&self, client_id: ClientId) -> Arc<Mutex<Account>>;
+
+    /// Look up an existing account without creating it.
+    fn get_account(&self, client_id: ClientId) -> Option<Arc<Mutex<Account>>>;
+
+    /// Add (or replace) an account.
+    fn insert(&self, account: Account);
+
+    /// Every account, in no particular order.
+    fn iter(&self) -> impl Iterator<Item = Arc<Mutex<Account>>> + '_;
+
+    /// The client id of every account, in no particular order, without
+    /// locking any account.
+    fn keys(&self) -> Vec<ClientId>;
+}
+
+/// The default store: a map of accounts in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// Wrapped in RwLock because account operations are far more common than
+    /// account creation/deletion.
+    data: RwLock<HashMap<ClientId, Arc<Mutex<Account>>>>,
+}
+
+impl AccountStore for MemoryStore {
+    fn account(&self, client_id: ClientId) -> Arc<Mutex<Account>> {
+        if let Some(account) = self.get_account(client_id) {
+            return account;
+        }
+
+        // Account does not exist, add it.
+        let mut data = self.data.write().expect("lock poisoned");
+        // NOTE: Another thread may have added it between releasing the read
+        // lock and taking the write lock, so don't just insert blindly.
+        data.entry(client_id)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Account::builder().client_id(client_id).build()))
+            })
+            .clone()
+    }
+
+    fn get_account(&self, client_id: ClientId) -> Option<Arc<Mutex<Account>>> {
+        self.data
+            .read()
+            .expect("lock poisoned")
+            .get(&client_id)
+            .cloned()
+    }
+
+    fn insert(&self, account: Account) {
+        self.data
+            .write()
+            .expect("lock poisoned")
+            .insert(account.client_id(), Arc::new(Mutex::new(account)));
+    }
+
+    /// The read lock is released before returning, so callers may iterate
+    /// freely without blocking account creation.
+    fn iter(&self) -> impl Iterator<Item = Arc<Mutex<Account>>> + '_ {
+        let accounts: Vec<_> = self
+            .data
+            .read()
+            .expect("lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+        accounts.into_iter()
+    }
+
+    fn keys(&self) -> Vec<ClientId> {
+        self.data
+            .read()
+            .expect("lock poisoned")
+            .keys()
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{account::AccountDatabase, currency::Currency, input, processor::Processor};
+
+    /// Counts lookups, to check that the processor goes through the store.
+    #[derive(Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        lookups: AtomicUsize,
+    }
+
+    impl AccountStore for CountingStore {
+        fn account(&self, client_id: ClientId) -> Arc<Mutex<Account>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.account(client_id)
+        }
+
+        fn get_account(&self, client_id: ClientId) -> Option<Arc<Mutex<Account>>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_account(client_id)
+        }
+
+        fn insert(&self, account: Account) {
+            self.inner.insert(account);
+        }
+
+        fn iter(&self) -> impl Iterator<Item = Arc<Mutex<Account>>> + '_ {
+            self.inner.iter()
+        }
+
+        fn keys(&self) -> Vec<ClientId> {
+            self.inner.keys()
+        }
+    }
+
+    #[test]
+    fn test_custom_store() {
+        let database = AccountDatabase::with_store(CountingStore::default(), 4);
+        let processor = Processor::new(database.clone());
+        let csv = "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,2,1.0\ndispute,1,1,\n";
+        input::process_csv(&processor, Cursor::new(csv)).unwrap();
+        processor.close_and_verify().unwrap();
+
+        assert_eq!(database.store().lookups.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            database.client_ids(),
+            vec![ClientId::from(1), ClientId::from(2)]
+        );
+        let account = database.get_account(ClientId::from(1)).unwrap();
+        assert_eq!(account.lock().unwrap().held, Currency::from_f64(2.5));
+    }
+}
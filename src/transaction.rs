@@ -1,18 +1,89 @@
 use std::{
     fmt::{Debug, Display},
+    num::IntErrorKind,
     str::FromStr,
 };
 
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 
-use crate::{account::ClientId, currency::Currency};
+use crate::{
+    account::ClientId,
+    currency::{Currency, NonNegativeCurrency},
+    error::TransactionError,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// Transaction ids are limited to `u32`; larger ids are rejected with a
+/// specific error rather than wrapping or truncating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct TransactionId(u32);
 
+fn id_too_large() -> anyhow::Error {
+    anyhow::anyhow!("transaction id exceeds maximum {}", u32::MAX)
+}
+
+impl TryFrom<u64> for TransactionId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Ok(Self(u32::try_from(value).map_err(|_| id_too_large())?))
+    }
+}
+
+/// Accepts a string (parsed with `FromStr`) or an integer, with the same
+/// errors either way. CSV fields are inferred, so anything that looks like
+/// another type is rejected as an invalid id rather than as a type mismatch.
+impl<'de> Deserialize<'de> for TransactionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TransactionIdVisitor;
+
+        impl Visitor<'_> for TransactionIdVisitor {
+            type Value = TransactionId;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a transaction id")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                TransactionId::try_from(v).map_err(E::custom)
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_u128<E: serde::de::Error>(self, _: u128) -> Result<Self::Value, E> {
+                Err(E::custom(id_too_large()))
+            }
+
+            fn visit_i128<E: serde::de::Error>(self, v: i128) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                match v.fract() == 0.0 && v > 0.0 {
+                    // An integer too large even for `u128`.
+                    true => Err(E::custom(id_too_large())),
+                    false => self.visit_str(&v.to_string()),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(TransactionIdVisitor)
+    }
+}
+
 impl From<u32> for TransactionId {
     fn from(value: u32) -> Self {
         Self(value)
@@ -29,7 +100,11 @@ impl FromStr for TransactionId {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.parse().context("invalid transaction id")?))
+        match s.parse() {
+            Ok(value) => Ok(Self(value)),
+            Err(e) if *e.kind() == IntErrorKind::PosOverflow => Err(id_too_large()),
+            Err(e) => Err(e).with_context(|| format!("invalid transaction id: {s}")),
+        }
     }
 }
 
@@ -53,19 +128,326 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Administrative undo of the account's most recent deposit or
+    /// withdrawal, referenced by its id.
+    Reversal,
+    /// Manual balance correction. The amount may be negative, and the
+    /// `reference` must give the reason.
+    Adjustment,
 }
 
+/// Only adjustments may have a negative amount. This is checked when the
+/// transaction is built (or parsed), which is why the type and amount can
+/// only be read from outside the crate.
 #[non_exhaustive]
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bon::Builder)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "TransactionRecord")]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[cfg_attr(test, proptest(filter = "|t| t.validate().is_ok()"))]
 pub struct Transaction {
     #[serde(rename = "type")]
-    pub transaction_type: TransactionType,
+    pub(crate) transaction_type: TransactionType,
     #[serde(rename = "client")]
     pub client_id: ClientId,
     #[serde(rename = "tx")]
     pub transaction_id: TransactionId,
-    #[builder(default)]
+    pub(crate) amount: Currency,
+    /// Optional external reference (memo) for reconciliation.
+    /// This is carried through to the history but never affects balances.
+    pub reference: Option<String>,
+    /// When the transaction occurred, if known (RFC 3339 in the input).
+    #[cfg_attr(test, proptest(value = "None"))]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Position in the feed, if the feed numbers its transactions.
+    /// Only used to detect gaps that may indicate dropped transactions.
+    #[cfg_attr(test, proptest(value = "None"))]
+    pub sequence: Option<u64>,
+    /// Whether the funds have settled. Only settled transactions can be
+    /// disputed. Defaults to true when not given.
+    #[cfg_attr(test, proptest(value = "true"))]
+    pub settled: bool,
+    /// The part of a withdrawal's amount that was charged as a fee (see
+    /// `ApplyOptions::withdrawal_fee`). Set when the withdrawal is applied,
+    /// so that reversing it refunds only the rest.
+    #[serde(default, skip_serializing_if = "Currency::is_zero")]
+    #[cfg_attr(test, proptest(value = "Currency::ZERO"))]
+    pub(crate) fee: Currency,
+    /// Whether a chargeback froze the account. Set when the chargeback is
+    /// applied, since partial chargebacks only freeze it with
+    /// `ApplyOptions::freeze_on_partial_chargeback`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[cfg_attr(test, proptest(value = "false"))]
+    pub(crate) froze_account: bool,
+}
+
+#[bon::bon]
+impl Transaction {
+    #[builder]
+    pub fn new(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        #[builder(default)] amount: Currency,
+        #[builder(into)] reference: Option<String>,
+        timestamp: Option<DateTime<Utc>>,
+        sequence: Option<u64>,
+        #[builder(default = true)] settled: bool,
+    ) -> Result<Self, TransactionError> {
+        let transaction = Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            reference,
+            timestamp,
+            sequence,
+            settled,
+            fee: Currency::ZERO,
+            froze_account: false,
+        };
+        transaction.validate()?;
+        Ok(transaction)
+    }
+
+    fn validate(&self) -> Result<(), TransactionError> {
+        if self.amount.is_negative() && self.transaction_type != TransactionType::Adjustment {
+            return Err(TransactionError::NegativeAmount {
+                client_id: self.client_id,
+                transaction_id: self.transaction_id,
+                amount: self.amount,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn amount(&self) -> Currency {
+        self.amount
+    }
+
+    pub fn fee(&self) -> Currency {
+        self.fee
+    }
+
+    pub fn froze_account(&self) -> bool {
+        self.froze_account
+    }
+
+    /// Columns used when writing transactions as CSV. These match the input
+    /// columns, so the output can be read back in.
+    pub(crate) const CSV_COLUMNS: [&'static str; 6] =
+        ["type", "client", "tx", "amount", "reference", "timestamp"];
+
+    /// The values for `CSV_COLUMNS`.
+    pub(crate) fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.transaction_type.to_string(),
+            self.client_id.to_string(),
+            self.transaction_id.to_string(),
+            self.amount.to_string(),
+            self.reference.clone().unwrap_or_default(),
+            self.timestamp.map(|x| x.to_rfc3339()).unwrap_or_default(),
+        ]
+    }
+}
+
+/// A transaction as it appears in the input, before validation.
+///
+/// Keeping the amount optional lets us distinguish a missing amount from
+/// one that is present, which only matters for some transaction types.
+#[derive(Deserialize)]
+pub(crate) struct TransactionRecord {
+    #[serde(rename = "type")]
+    pub(crate) transaction_type: TransactionType,
+    #[serde(rename = "client")]
+    pub(crate) client_id: ClientId,
+    #[serde(rename = "tx")]
+    pub(crate) transaction_id: TransactionId,
+    /// Only adjustments may be negative, which `Transaction::new` checks.
+    #[serde(default)]
+    pub(crate) amount: Option<Currency>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub(crate) reference: Option<String>,
+    #[serde(default)]
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) sequence: Option<u64>,
+    #[serde(default)]
+    pub(crate) settled: Option<bool>,
+    /// Only present for withdrawals read back from a history. See
+    /// `Transaction::fee`.
     #[serde(default)]
-    pub amount: Currency,
+    pub(crate) fee: Option<NonNegativeCurrency>,
+    /// Only present for chargebacks read back from a history. See
+    /// `Transaction::froze_account`.
+    #[serde(default)]
+    pub(crate) froze_account: Option<bool>,
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = anyhow::Error;
+
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let amount = match (record.transaction_type, record.amount) {
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Adjustment,
+                None,
+            ) => {
+                return Err(anyhow::anyhow!(
+                    "amount required for {} (tx {})",
+                    record.transaction_type,
+                    record.transaction_id
+                ));
+            }
+            (_, amount) => amount.unwrap_or_default(),
+        };
+        if record.transaction_type == TransactionType::Adjustment && record.reference.is_none() {
+            return Err(anyhow::anyhow!(
+                "reference required for adjustment (tx {})",
+                record.transaction_id
+            ));
+        }
+
+        let fee = record.fee.map(Currency::from).unwrap_or_default();
+        if !fee.is_zero()
+            && (record.transaction_type != TransactionType::Withdrawal || fee > amount)
+        {
+            return Err(anyhow::anyhow!(
+                "invalid fee of {fee} for {} (tx {})",
+                record.transaction_type,
+                record.transaction_id
+            ));
+        }
+
+        let froze_account = record.froze_account.unwrap_or_default();
+        if froze_account && record.transaction_type != TransactionType::Chargeback {
+            return Err(anyhow::anyhow!(
+                "only a chargeback can freeze the account (tx {})",
+                record.transaction_id
+            ));
+        }
+
+        let mut transaction = Self::builder()
+            .transaction_type(record.transaction_type)
+            .client_id(record.client_id)
+            .transaction_id(record.transaction_id)
+            .amount(amount)
+            .maybe_reference(record.reference)
+            .maybe_timestamp(record.timestamp)
+            .maybe_sequence(record.sequence)
+            .maybe_settled(record.settled)
+            .build()?;
+        transaction.fee = fee;
+        transaction.froze_account = froze_account;
+        Ok(transaction)
+    }
+}
+
+/// Treat an empty string the same as a missing value.
+fn empty_string_as_none<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.filter(|s| !s.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_id_from_str() {
+        assert_eq!(
+            TransactionId::from_str("4294967295").unwrap(),
+            TransactionId::from(u32::MAX)
+        );
+
+        let err = TransactionId::from_str("4294967296").expect_err("overflow");
+        assert_eq!(err.to_string(), "transaction id exceeds maximum 4294967295");
+
+        let err = TransactionId::from_str("abc").expect_err("not a number");
+        assert_eq!(err.to_string(), "invalid transaction id: abc");
+    }
+
+    #[test]
+    fn test_transaction_id_deserialize_overflow() {
+        let err = serde_json::from_str::<TransactionId>("4294967296").expect_err("overflow");
+        assert!(err
+            .to_string()
+            .contains("transaction id exceeds maximum 4294967295"));
+        assert_eq!(
+            serde_json::from_str::<TransactionId>("7").unwrap(),
+            TransactionId::from(7)
+        );
+    }
+
+    #[test]
+    fn test_negative_amount_rejected() {
+        let build = |transaction_type| {
+            Transaction::builder()
+                .transaction_type(transaction_type)
+                .client_id(ClientId::from(1))
+                .transaction_id(TransactionId::from(7))
+                .amount("-2.5".parse().unwrap())
+                .reference("correction")
+                .build()
+        };
+        let err = build(TransactionType::Deposit).expect_err("negative deposit");
+        assert_eq!(
+            err.to_string(),
+            "transaction amount must not be negative: -2.5 (client 1, tx 7)"
+        );
+        // Adjustments can debit as well as credit.
+        assert!(build(TransactionType::Adjustment).is_ok());
+    }
+
+    #[test]
+    fn test_fee_deserialize() {
+        let parse = |json: &str| serde_json::from_str::<Transaction>(json);
+        let withdrawal =
+            parse(r#"{"type":"withdrawal","client":"1","tx":2,"amount":"3.0","fee":"1.0"}"#)
+                .unwrap();
+        assert_eq!(withdrawal.fee(), Currency::from_f64(1.0));
+
+        for json in [
+            r#"{"type":"deposit","client":"1","tx":2,"amount":"3.0","fee":"1.0"}"#,
+            r#"{"type":"withdrawal","client":"1","tx":2,"amount":"3.0","fee":"4.0"}"#,
+        ] {
+            let err = parse(json).expect_err(json);
+            assert!(err.to_string().contains("invalid fee"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_transaction_id_deserialize_csv() {
+        let parse = |tx: &str| {
+            let input = format!("type,client,tx,amount\ndeposit,1,{tx},1.0\n");
+            csv::Reader::from_reader(input.as_bytes())
+                .deserialize::<TransactionRecord>()
+                .next()
+                .unwrap()
+                .map(|record| record.transaction_id)
+        };
+
+        assert_eq!(parse("4294967295").unwrap(), TransactionId::from(u32::MAX));
+        for (tx, expected) in [
+            ("abc", "invalid transaction id: abc"),
+            ("-1", "invalid transaction id: -1"),
+            ("1.5", "invalid transaction id: 1.5"),
+            ("true", "invalid transaction id: true"),
+            ("4294967296", "transaction id exceeds maximum 4294967295"),
+            (
+                "123456789012345678901234",
+                "transaction id exceeds maximum 4294967295",
+            ),
+            (&"9".repeat(50), "transaction id exceeds maximum 4294967295"),
+        ] {
+            let err = parse(tx).expect_err(tx);
+            assert!(err.to_string().contains(expected), "{tx}: {err}");
+        }
+    }
 }